@@ -25,10 +25,103 @@ pub const LOCAL_MEMTRIM_LIMIT: usize = 16384;
 /// The local memtrimming will continue until the allocator has less memory (in bytes, of course)
 /// than this value.
 pub const LOCAL_MEMTRIM_STOP: usize = 1024;
+/// The window of the local memtrim policy, in allocations and frees.
+///
+/// The allocation volume of a thread is sampled over windows of this many operations, and the
+/// local memtrim limit is raised to the (smoothed) volume per window. Frees complete windows as
+/// well, such that the limit decays, when the thread stops allocating.
+pub const LOCAL_MEMTRIM_WINDOW: usize = 256;
+/// The maximal local memtrim limit.
+///
+/// The adaptive local memtrim limit never exceeds this value.
+pub const LOCAL_MEMTRIM_MAX: usize = 16777216;
+/// The number of recently reused addresses, which are tracked per thread.
+///
+/// Free blocks containing these are considered hot, and are kept by the local memtrim, if possible.
+pub const HOT_BLOCKS: usize = 16;
+
+/// The number of fitting blocks to choose between in `security` mode.
+///
+/// Rather than always using the first fitting block, a random one among the first
+/// `RANDOM_CANDIDATES` fitting blocks is chosen, making the heap layout harder to predict.
+pub const RANDOM_CANDIDATES: usize = 8;
+/// The maximal random offset into an oversized block in `security` mode.
+pub const RANDOM_MAX_OFFSET: usize = 1024;
+/// The seed of the pseudo-random number generator.
+///
+/// `None` seeds from the OS. Setting this makes the placement in `security` mode deterministic,
+/// which is useful for reproducing failures.
+pub const RANDOM_SEED: Option<u64> = None;
+
+/// The quarantine byte budget.
+///
+/// In `quarantine` mode, freed blocks are held back from reuse, until the quarantine of the
+/// thread holds more bytes than this value.
+pub const QUARANTINE_BUDGET: usize = 262144;
+/// The number of blocks a quarantine can hold.
+///
+/// When exceeded, adjacent blocks are merged, and if that is not possible, the oldest block is
+/// released early.
+pub const QUARANTINE_SLOTS: usize = 64;
+
+/// The byte pattern free blocks are filled with in `poison` mode.
+///
+/// Whenever a block is handed out again, it is verified that this pattern is intact.
+pub const FREE_POISON: u8 = 0x5a;
+/// The byte pattern new allocations are filled with in `poison` mode.
+///
+/// `None` leaves new allocations untouched.
+pub const UNINIT_POISON: Option<u8> = Some(0xa5);
 
 /// The minimum log level.
 pub const MIN_LOG_LEVEL: u8 = 0;
 
+/// The number of allocator operations between heap verifications.
+///
+/// Every this many operations, the block pools are verified, and inconsistencies are reported to
+/// the heap corruption handler. Zero disables the verification.
+pub const VERIFY_INTERVAL: usize = 0;
+
+/// The maximal number of times a request is retried, when asked by the OOM handler.
+pub const OOM_RETRIES: usize = 8;
+
+/// The size of the emergency arena.
+///
+/// Allocations made from inside the allocator (e.g. by a heap corruption handler) are served from
+/// this arena, which is never reused.
+pub const EMERGENCY_ARENA_SIZE: usize = 16384;
+/// The number of frees made from inside the allocator, which can be deferred per thread.
+///
+/// When exceeded, the buffers are leaked.
+pub const DEFERRED_FREES: usize = 32;
+
+/// The utilization threshold of the block pools.
+///
+/// When the capacity of a block pool is more than this many times what it needs, compacting it
+/// moves it to a smaller segment.
+pub const POOL_SHRINK_FACTOR: usize = 4;
+
+/// The page size of the OS.
+///
+/// This is used for purging the pages of free blocks.
+pub const PAGE_SIZE: usize = 4096;
+
+/// The size of the buffer of the simulated program break.
+///
+/// This is only used when ralloc is compiled with `simulation`.
+pub const SIM_BUFFER_SIZE: usize = 1 << 26;
+
+/// The environment variable holding the runtime configuration.
+pub const CONF_VAR: &'static [u8] = b"RALLOC_CONF";
+/// The file containing the environment of the process (NUL-terminated path).
+///
+/// The entries are separated by NUL bytes.
+pub const ENVIRON_PATH: &'static [u8] = b"/proc/self/environ\0";
+/// The file containing the auxiliary vector of the process (NUL-terminated path).
+///
+/// This is used for seeding the random number generators, when the OS provides no `getrandom`.
+pub const AUXV_PATH: &'static [u8] = b"/proc/self/auxv\0";
+
 /// The default OOM handler.
 #[cold]
 pub fn default_oom_handler() -> ! {
@@ -47,25 +140,44 @@ pub fn log(s: &str) -> usize {
     unsafe { syscall!(WRITE, 2, s.as_ptr(), s.len()) }
 }
 
+/// The multiplier of fresh allocations.
+///
+/// The factor determining the linear dependence between the minimum segment, and the acquired
+/// segment.
+pub const FRESH_MULTIPLIER: usize = 2;
+/// The minimum extra size of fresh allocations.
+pub const FRESH_MIN_EXTRA: usize = 64;
+/// The maximal amount of _extra_ bytes of fresh allocations.
+pub const FRESH_MAX_EXTRA: usize = 1024;
+
 /// Canonicalize a fresh allocation.
 ///
 /// The return value specifies how much _more_ space is requested to the fresh allocator.
 // TODO: Move to shim.
 #[inline]
 pub fn extra_fresh(size: usize) -> usize {
-    /// The multiplier.
-    ///
-    /// The factor determining the linear dependence between the minimum segment, and the acquired
-    /// segment.
-    const MULTIPLIER: usize = 2;
-    /// The minimum extra size to be BRK'd.
-    const MIN_EXTRA: usize = 64;
-    /// The maximal amount of _extra_ bytes.
-    const MAX_EXTRA: usize = 1024;
-
-    cmp::max(MIN_EXTRA, cmp::min(MULTIPLIER * size, MAX_EXTRA))
+    cmp::max(FRESH_MIN_EXTRA, cmp::min(size.saturating_mul(FRESH_MULTIPLIER), FRESH_MAX_EXTRA))
 }
 
+// TODO: Tweak this.
+/// The BRK multiplier.
+///
+/// The factor determining the linear dependence between the minimum segment, and the acquired
+/// segment.
+pub const BRK_MULTIPLIER: usize = 2;
+/// The minimum extra size to be BRK'd.
+pub const BRK_MIN_EXTRA: usize = 1024;
+/// The maximal amount of _extra_ bytes.
+pub const BRK_MAX_EXTRA: usize = 65536;
+/// The maximal amount of _extra_ bytes, when growing rapidly.
+///
+/// When the program break is extended repeatedly within `BRK_GROWTH_WINDOW` allocations, the
+/// extra space is doubled on every extension, up to this bound. When the growth stops, it decays
+/// back to the curve of `extra_brk`.
+pub const BRK_ADAPTIVE_MAX: usize = 16777216;
+/// The window of rapid growth of the program break, in allocations.
+pub const BRK_GROWTH_WINDOW: usize = 256;
+
 /// Canonicalize a BRK request.
 ///
 /// Syscalls can be expensive, which is why we would rather accquire more memory than necessary,
@@ -79,16 +191,5 @@ pub fn extra_fresh(size: usize) -> usize {
 // TODO: Move to shim.
 #[inline]
 pub fn extra_brk(size: usize) -> usize {
-    // TODO: Tweak this.
-    /// The BRK multiplier.
-    ///
-    /// The factor determining the linear dependence between the minimum segment, and the acquired
-    /// segment.
-    const MULTIPLIER: usize = 2;
-    /// The minimum extra size to be BRK'd.
-    const MIN_EXTRA: usize = 1024;
-    /// The maximal amount of _extra_ bytes.
-    const MAX_EXTRA: usize = 65536;
-
-    cmp::max(MIN_EXTRA, cmp::min(MULTIPLIER * size, MAX_EXTRA))
+    cmp::max(BRK_MIN_EXTRA, cmp::min(size.saturating_mul(BRK_MULTIPLIER), BRK_MAX_EXTRA))
 }
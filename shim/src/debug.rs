@@ -1,17 +1,227 @@
 //! Bindings to debuggers.
+//!
+//! The allocator reports the state of its memory through the hooks of this module, which are
+//! forwarded to a backend implementing [`Debugger`](./trait.Debugger.html):
+//!
+//! - [`Valgrind`](./struct.Valgrind.html) (default) issues Valgrind client requests. These are
+//!   magic instruction sequences, which Valgrind recognizes, while they are no-ops when running
+//!   natively. On architectures without client requests, this does nothing.
+//! - [`Asan`](./struct.Asan.html) (with the `asan` feature) poisons free memory to
+//!   AddressSanitizer.
 
+/// A debugger backend.
+///
+/// Every hook has a default implementation doing nothing, such that backends only need to
+/// implement the hooks they care about.
+pub trait Debugger {
+    /// Mark a segment free (inaccessible).
+    fn mark_free(_ptr: *const u8, _size: usize) {}
+    /// Mark a segment uninitialized (accessible, but undefined).
+    fn mark_uninitialized(_ptr: *const u8, _size: usize) {}
+    /// Mark a segment defined (accessible and initialized).
+    fn mark_defined(_ptr: *const u8, _size: usize) {}
+    /// Report that a buffer was allocated.
+    fn malloclike(_ptr: *const u8, _size: usize, _zeroed: bool) {}
+    /// Report that the buffer starting at `ptr` was freed.
+    fn freelike(_ptr: *const u8) {}
+    /// Report that the buffer starting at `ptr` was resized inplace.
+    fn resize_inplace(_ptr: *const u8, _old_size: usize, _size: usize) {}
+}
+
+/// The backend in use.
+#[cfg(not(feature = "asan"))]
+pub type Backend = Valgrind;
+/// The backend in use.
+#[cfg(feature = "asan")]
+pub type Backend = Asan;
+
+/// The Valgrind backend.
+pub struct Valgrind;
+
+/// The `MALLOCLIKE_BLOCK` client request.
+const MALLOCLIKE_BLOCK: usize = 0x1301;
+/// The `FREELIKE_BLOCK` client request.
+const FREELIKE_BLOCK: usize = 0x1302;
+/// The `RESIZEINPLACE_BLOCK` client request.
+const RESIZEINPLACE_BLOCK: usize = 0x130b;
+/// The base of the Memcheck client requests (`'M'` and `'C'`).
+const MEMCHECK_BASE: usize = 0x4d430000;
+/// The `MAKE_MEM_NOACCESS` client request of Memcheck.
+const MAKE_MEM_NOACCESS: usize = MEMCHECK_BASE;
+/// The `MAKE_MEM_UNDEFINED` client request of Memcheck.
+const MAKE_MEM_UNDEFINED: usize = MEMCHECK_BASE + 1;
+/// The `MAKE_MEM_DEFINED` client request of Memcheck.
+const MAKE_MEM_DEFINED: usize = MEMCHECK_BASE + 2;
+
+/// Make a Valgrind client request.
+///
+/// The arguments are the request and its (up to) five parameters. When not running under
+/// Valgrind, `default` is returned.
+#[inline]
+fn request(default: usize, args: [usize; 6]) -> usize {
+    arch::request(default, &args)
+}
+
+/// Client requests for x86-64.
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    /// Make a client request.
+    #[inline(always)]
+    pub fn request(default: usize, args: &[usize; 6]) -> usize {
+        let res;
+
+        unsafe {
+            // The rotations of `rdi` add up to 128 bits, leaving it intact.
+            asm!("rolq $$3, %rdi
+                  rolq $$13, %rdi
+                  rolq $$61, %rdi
+                  rolq $$51, %rdi
+                  xchgq %rbx, %rbx"
+                 : "={rdx}"(res)
+                 : "{rax}"(args.as_ptr()), "{rdx}"(default)
+                 : "cc", "memory"
+                 : "volatile");
+        }
+
+        res
+    }
+}
+
+/// Client requests for AArch64.
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    /// Make a client request.
+    #[inline(always)]
+    pub fn request(default: usize, args: &[usize; 6]) -> usize {
+        let res;
+
+        unsafe {
+            // The rotations of `x12` add up to 128 bits, leaving it intact.
+            asm!("ror x12, x12, #3
+                  ror x12, x12, #13
+                  ror x12, x12, #51
+                  ror x12, x12, #61
+                  orr x10, x10, x10"
+                 : "={x3}"(res)
+                 : "{x4}"(args.as_ptr()), "{x3}"(default)
+                 : "cc", "memory"
+                 : "volatile");
+        }
+
+        res
+    }
+}
+
+/// Client requests for other architectures (unsupported).
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod arch {
+    /// Make a client request.
+    ///
+    /// This is not supported on this architecture, and does nothing.
+    #[inline(always)]
+    pub fn request(default: usize, _: &[usize; 6]) -> usize {
+        default
+    }
+}
+
+impl Debugger for Valgrind {
+    fn mark_free(ptr: *const u8, size: usize) {
+        request(0, [MAKE_MEM_NOACCESS, ptr as usize, size, 0, 0, 0]);
+    }
+
+    fn mark_uninitialized(ptr: *const u8, size: usize) {
+        request(0, [MAKE_MEM_UNDEFINED, ptr as usize, size, 0, 0, 0]);
+    }
+
+    fn mark_defined(ptr: *const u8, size: usize) {
+        request(0, [MAKE_MEM_DEFINED, ptr as usize, size, 0, 0, 0]);
+    }
+
+    fn malloclike(ptr: *const u8, size: usize, zeroed: bool) {
+        request(0, [MALLOCLIKE_BLOCK, ptr as usize, size, 0, zeroed as usize, 0]);
+    }
+
+    fn freelike(ptr: *const u8) {
+        request(0, [FREELIKE_BLOCK, ptr as usize, 0, 0, 0, 0]);
+    }
+
+    fn resize_inplace(ptr: *const u8, old_size: usize, size: usize) {
+        request(0, [RESIZEINPLACE_BLOCK, ptr as usize, old_size, size, 0, 0]);
+    }
+}
+
+/// The AddressSanitizer backend.
+///
+/// This requires the program to be built with `-Zsanitizer=address`, which provides the poisoning
+/// interface.
+#[cfg(feature = "asan")]
+pub struct Asan;
+
+#[cfg(feature = "asan")]
 extern {
-    /// Valgrind symbol to declare memory undefined.
-    fn valgrind_make_mem_undefined(ptr: *const u8, size: usize);
-    /// Valgrind symbol to declare memory freed.
-    fn valgrind_freelike_block(ptr: *const u8, size: usize);
+    /// Mark a segment inaccessible to AddressSanitizer.
+    fn __asan_poison_memory_region(ptr: *const u8, size: usize);
+    /// Mark a segment accessible to AddressSanitizer.
+    fn __asan_unpoison_memory_region(ptr: *const u8, size: usize);
 }
 
-/// Mark this segment undefined to the debugger.
-pub fn mark_undefined(ptr: *const u8, size: usize) {
-    unsafe { valgrind_make_mem_undefined(ptr, size) }
+#[cfg(feature = "asan")]
+impl Debugger for Asan {
+    fn mark_free(ptr: *const u8, size: usize) {
+        unsafe { __asan_poison_memory_region(ptr, size) }
+    }
+
+    fn mark_uninitialized(ptr: *const u8, size: usize) {
+        unsafe { __asan_unpoison_memory_region(ptr, size) }
+    }
+
+    fn mark_defined(ptr: *const u8, size: usize) {
+        unsafe { __asan_unpoison_memory_region(ptr, size) }
+    }
+
+    fn malloclike(ptr: *const u8, size: usize, _: bool) {
+        unsafe { __asan_unpoison_memory_region(ptr, size) }
+    }
+
+    fn resize_inplace(ptr: *const u8, old_size: usize, size: usize) {
+        if size > old_size {
+            unsafe { __asan_unpoison_memory_region(ptr, size) }
+        } else if size < old_size {
+            // The tail is no longer part of the buffer.
+            let tail = (ptr as usize + size) as *const u8;
+            unsafe { __asan_poison_memory_region(tail, old_size - size) }
+        }
+    }
 }
-/// Mark this segment free to the debugger.
+
+/// Mark this segment free (inaccessible) to the debugger.
 pub fn mark_free(ptr: *const u8, size: usize) {
-    unsafe { valgrind_freelike_block(ptr, size) }
+    Backend::mark_free(ptr, size)
+}
+
+/// Mark this segment uninitialized (accessible, but undefined) to the debugger.
+pub fn mark_uninitialized(ptr: *const u8, size: usize) {
+    Backend::mark_uninitialized(ptr, size)
+}
+
+/// Mark this segment defined (accessible and initialized) to the debugger.
+pub fn mark_defined(ptr: *const u8, size: usize) {
+    Backend::mark_defined(ptr, size)
+}
+
+/// Tell the debugger that a buffer was allocated.
+///
+/// This makes the buffer accessible, and tracks it for leaks and invalid frees.
+pub fn malloclike(ptr: *const u8, size: usize, zeroed: bool) {
+    Backend::malloclike(ptr, size, zeroed)
+}
+
+/// Tell the debugger that the buffer starting at `ptr` was freed.
+pub fn freelike(ptr: *const u8) {
+    Backend::freelike(ptr)
+}
+
+/// Tell the debugger that the buffer starting at `ptr` was resized inplace.
+pub fn resize_inplace(ptr: *const u8, old_size: usize, size: usize) {
+    Backend::resize_inplace(ptr, old_size, size)
 }
@@ -0,0 +1,50 @@
+//! Fork handlers.
+//!
+//! This module supplies the ability to register handlers called around `fork`.
+
+pub use self::arch::*;
+
+/// Fork handlers for Linux/BSD.
+#[cfg(not(target_os = "macos"))]
+pub mod arch {
+    extern {
+        #[linkage = "extern_weak"]
+        static pthread_atfork: *const u8;
+    }
+
+    /// Register fork handlers through `pthread_atfork`.
+    ///
+    /// If `pthread_atfork` is not available, `false` is returned.
+    pub fn register(prepare: unsafe extern fn(), parent: unsafe extern fn(),
+                    child: unsafe extern fn()) -> bool {
+        use core::mem;
+
+        /// The `pthread_atfork` function.
+        type AtFork = unsafe extern fn(prepare: unsafe extern fn(), parent: unsafe extern fn(),
+                                       child: unsafe extern fn()) -> i32;
+
+        // Make sure the symbol exists.
+        if pthread_atfork.is_null() {
+            return false;
+        }
+
+        unsafe {
+            mem::transmute::<*const u8, AtFork>(pthread_atfork)(prepare, parent, child) == 0
+        }
+    }
+}
+
+/// Fork handlers for Mac OS.
+#[cfg(target_os = "macos")]
+pub mod arch {
+    extern {
+        fn pthread_atfork(prepare: unsafe extern fn(), parent: unsafe extern fn(),
+                          child: unsafe extern fn()) -> i32;
+    }
+
+    /// Register fork handlers through `pthread_atfork`.
+    pub fn register(prepare: unsafe extern fn(), parent: unsafe extern fn(),
+                    child: unsafe extern fn()) -> bool {
+        unsafe { pthread_atfork(prepare, parent, child) == 0 }
+    }
+}
@@ -7,7 +7,7 @@
 //! You CANNOT use libc library calls, due to no guarantees being made about allocations of the
 //! functions in the POSIX specification. Therefore, we use the system calls directly.
 
-#![feature(linkage, core_intrinsics)]
+#![feature(linkage, core_intrinsics, asm)]
 #![no_std]
 #![warn(missing_docs)]
 
@@ -15,6 +15,7 @@
 extern crate sc;
 
 pub mod config;
+pub mod fork;
 pub mod thread_destructor;
 pub mod debug;
 pub mod syscalls;
@@ -11,7 +11,82 @@ pub unsafe fn brk(ptr: *const u8) -> *const u8 {
     syscall!(BRK, ptr) as *const u8
 }
 
+/// Open a file for reading. See `man openat`.
+///
+/// `path` must be NUL-terminated, and relative paths are relative to the working directory. On
+/// success, the file descriptor is returned. On failure, a negated error code is returned.
+///
+/// This uses `openat`, since some architectures (e.g. AArch64) have no `open` system call.
+pub fn open_read(path: &[u8]) -> usize {
+    debug_assert!(path.last() == Some(&0), "The path is not NUL-terminated.");
+
+    /// The `AT_FDCWD` directory file descriptor (-100), denoting the working directory.
+    const AT_FDCWD: usize = !99;
+    /// The `O_RDONLY` flag.
+    const O_RDONLY: usize = 0;
+
+    unsafe { syscall!(OPENAT, AT_FDCWD, path.as_ptr(), O_RDONLY) }
+}
+
+/// Read from a file descriptor. See `man read`.
+///
+/// On success, the number of bytes read is returned (zero at end of file). On failure, a negated
+/// error code is returned.
+pub fn read(fd: usize, buf: &mut [u8]) -> usize {
+    unsafe { syscall!(READ, fd, buf.as_mut_ptr(), buf.len()) }
+}
+
+/// Write to a file descriptor. See `man write`.
+///
+/// On success, the number of bytes written is returned. On failure, a negated error code is
+/// returned.
+pub fn write(fd: usize, buf: &[u8]) -> usize {
+    unsafe { syscall!(WRITE, fd, buf.as_ptr(), buf.len()) }
+}
+
+/// Close a file descriptor. See `man close`.
+pub fn close(fd: usize) -> usize {
+    unsafe { syscall!(CLOSE, fd) }
+}
+
+/// Tell the OS that some pages are unused. See `man madvise` (`MADV_DONTNEED`).
+///
+/// The physical memory of the pages is reclaimed, and their content is lost (reading them yields
+/// zeros). On success, zero is returned. On failure, a negated error code is returned.
+#[cfg(target_os = "linux")]
+pub unsafe fn madvise_dontneed(ptr: *mut u8, len: usize) -> usize {
+    /// The `MADV_DONTNEED` advice.
+    const MADV_DONTNEED: usize = 4;
+
+    syscall!(MADVISE, ptr, len, MADV_DONTNEED)
+}
+
+/// Tell the OS that some pages are unused.
+///
+/// This is not supported on this platform, and will always fail.
+#[cfg(not(target_os = "linux"))]
+pub unsafe fn madvise_dontneed(_: *mut u8, _: usize) -> usize {
+    !0
+}
+
 /// Voluntarily give a time slice to the scheduler.
 pub fn sched_yield() -> usize {
     unsafe { syscall!(SCHED_YIELD) }
 }
+
+/// Fill a buffer with random bytes from the OS. See `man getrandom`.
+///
+/// On success, the number of bytes written is returned. On failure, a negated error code is
+/// returned.
+#[cfg(target_os = "linux")]
+pub fn getrandom(buf: &mut [u8]) -> usize {
+    unsafe { syscall!(GETRANDOM, buf.as_mut_ptr(), buf.len(), 0) }
+}
+
+/// Fill a buffer with random bytes from the OS.
+///
+/// This is not supported on this platform, and will always fail.
+#[cfg(not(target_os = "linux"))]
+pub fn getrandom(_: &mut [u8]) -> usize {
+    !0
+}
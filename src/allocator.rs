@@ -4,15 +4,25 @@
 
 use prelude::*;
 
-use core::{mem, ops};
+use core::{cmp, mem, ops, ptr};
+use core::ptr::NonNull;
 
-use {brk, sync};
+use {brk, emergency, env, sync, fail, limit, pressure, stats, verify};
 use bookkeeper::{self, Bookkeeper, Allocator};
+use fail::{AllocError, OomInfo};
+#[cfg(feature = "validate")]
+use owned;
 
-use shim::config;
+use config::{self, Param};
 
 #[cfg(feature = "tls")]
 use tls;
+#[cfg(feature = "quarantine")]
+use fail::Corruption;
+#[cfg(feature = "tls")]
+use policy::TrimPolicy;
+#[cfg(feature = "quarantine")]
+use quarantine::Quarantine;
 
 /// Alias for the wrapper type of the thread-local variable holding the local allocator.
 #[cfg(feature = "tls")]
@@ -35,6 +45,7 @@ tls! {
 /// 1. Initialize the allocator if needed.
 /// 2. If the allocator is not yet initialized, fallback to the global allocator.
 /// 3. Unlock/move temporarily out of reference.
+/// 4. Mark the thread as being inside the allocator, and free the deferred buffers afterwards.
 ///
 /// This is a macro due to the lack of generic closure, which makes it impossible to have one
 /// closure for both cases (global and local).
@@ -45,8 +56,12 @@ macro_rules! get_allocator {
         // Get the thread allocator, if TLS is enabled
         #[cfg(feature = "tls")]
         {
-            THREAD_ALLOCATOR.with(|thread_alloc| {
-                if let Some(mut thread_alloc_original) = thread_alloc.replace(None) {
+            let res = THREAD_ALLOCATOR.with(|thread_alloc| {
+                // Mark the thread as being inside the allocator, such that nested calls are not
+                // served by the allocator in use.
+                emergency::enter();
+
+                let res = if let Some(mut thread_alloc_original) = thread_alloc.replace(None) {
                     let res = {
                         // Call the closure involved.
                         let $v = thread_alloc_original.get();
@@ -68,8 +83,17 @@ macro_rules! get_allocator {
                     // Call the block in question.
                     let $v = guard.get();
                     $b
-                }
-            })
+                };
+
+                emergency::leave();
+
+                res
+            });
+
+            // Free the buffers, which were freed from inside the allocator.
+            flush_deferred();
+
+            res
         }
 
         // TLS is disabled, just use the global allocator.
@@ -114,6 +138,10 @@ macro_rules! derive_deref {
 struct GlobalAllocator {
     // The inner bookkeeper.
     inner: Bookkeeper,
+    /// Is the allocator currently memtrimming?
+    ///
+    /// This avoids memtrimming recursively, when a block is pushed back.
+    memtrimming: bool,
 }
 
 impl GlobalAllocator {
@@ -122,9 +150,14 @@ impl GlobalAllocator {
         /// Logging...
         log!(NOTE, "Initializing the global allocator.");
 
+        // Read the runtime configuration from the environment.
+        env::init();
+
         // The initial acquired segment.
+        let size = 4 * bookkeeper::EXTRA_ELEMENTS * mem::size_of::<Block>();
         let (aligner, initial_segment, excessive) =
-            brk::lock().canonical_brk(4 * bookkeeper::EXTRA_ELEMENTS * mem::size_of::<Block>(), mem::align_of::<Block>());
+            brk::lock().canonical_brk(size, mem::align_of::<Block>())
+                .unwrap_or_else(|err| fail::oom_fatal(&OomInfo::new(size, mem::align_of::<Block>(), err, true)));
 
         // Initialize the new allocator.
         let mut res = GlobalAllocator {
@@ -133,6 +166,7 @@ impl GlobalAllocator {
 
                 Vec::from_raw_parts(initial_segment, 0)
             }),
+            memtrimming: false,
         };
 
         // Free the secondary space.
@@ -141,15 +175,84 @@ impl GlobalAllocator {
 
         res
     }
+
+    /// Release the top block to the OS, if it has at least `worthy` bytes.
+    ///
+    /// The number of bytes released is returned.
+    fn memtrim(&mut self, worthy: usize) -> usize {
+        // Pop the last block.
+        let block = match self.pop() {
+            Some(block) => block,
+            None => return 0,
+        };
+        let size = block.size();
+
+        self.memtrimming = true;
+
+        // Check if the memtrim is worth it.
+        let res = if size >= worthy {
+            /// Logging...
+            log!(NOTE, "Memtrimming the global allocator.");
+
+            // Release the block to the OS. Note that the BRK lock must be released before pushing,
+            // since that might need to BRK.
+            let released = brk::lock().release(block);
+            match released {
+                Ok(()) => {
+                    // The pool might be sparse now.
+                    self.schedule_compaction();
+
+                    size
+                },
+                Err(block) => {
+                    // It failed, put the block back.
+                    // TODO: This can be done faster.
+                    self.push_prepared(block);
+
+                    0
+                },
+            }
+
+            // Note that this block is the only block next to the program break, due to the
+            // segments being as long as possible. For that reason, repeating to push and
+            // release would fail.
+        } else {
+            /// Logging...
+            log!(WARNING, "Memtrimming for the global allocator failed.");
+
+            // Push the block back.
+            // TODO: This can be done faster.
+            self.push_prepared(block);
+
+            0
+        };
+
+        self.memtrimming = false;
+
+        res
+    }
 }
 
 derive_deref!(GlobalAllocator, Bookkeeper);
 
 impl Allocator for GlobalAllocator {
     #[inline]
-    fn alloc_fresh(&mut self, size: usize, align: usize) -> Block {
+    fn is_global(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn alloc_fresh(&mut self, size: usize, align: usize) -> Result<Block, AllocError> {
+        // Make sure that pushing the aligner and the excessive block won't need to reserve, since
+        // that could fail after the segment has been acquired, leaking it.
+        if let Some(x) = unborrow!(self.reserve(self.len() + 2))? {
+            self.free(x);
+        }
+
         // Obtain what you need.
-        let (alignment_block, res, excessive) = brk::lock().canonical_brk(size, align);
+        let segment = brk::lock().canonical_brk(size, align);
+        notify_source(&segment, size);
+        let (alignment_block, res, excessive) = segment?;
 
         // Add it to the list. This will not change the order, since the pointer is higher than all
         // the previous blocks (BRK extends the data segment). Although, it is worth noting that
@@ -157,41 +260,86 @@ impl Allocator for GlobalAllocator {
         self.push(alignment_block);
         self.push(excessive);
 
-        res
+        Ok(res)
+    }
+
+    #[inline]
+    fn extend_fresh(&mut self, end: &Block, size: usize) -> Result<Block, AllocError> {
+        extend_brk(end, size)
     }
 
     fn on_new_memory(&mut self) {
-        if self.total_bytes() > config::OS_MEMTRIM_LIMIT {
-            // memtrim the fack outta 'em.
+        // Check if the heap has grown beyond a high-water mark.
+        pressure::check_heap();
 
-            // Pop the last block.
-            let block = self.pop().expect("The byte count on the global allocator is invalid.");
+        if !self.memtrimming && self.total_bytes() > config::get(Param::MemtrimLimit) {
+            // memtrim the fack outta 'em.
+            self.memtrim(config::get(Param::MemtrimWorthy));
+        }
+    }
+}
 
-            // Check if the memtrim is worth it.
-            if block.size() >= config::OS_MEMTRIM_WORTHY {
-                /// Logging...
-                log!(NOTE, "Memtrimming the global allocator.");
+/// Free the blocks of a local allocator to the global allocator, until it holds less than `stop`
+/// bytes.
+///
+/// The blocks, which weren't reused recently (according to `policy`), are freed first, and the
+/// top blocks after them.
+#[cfg(feature = "tls")]
+fn trim_local<A, B>(local: &mut A, policy: &TrimPolicy, global: &mut B, stop: usize)
+    where A: Allocator, B: Allocator {
+    // Free the cold blocks. Removing a block keeps the indices below it intact.
+    let mut ind = local.len();
+    while ind > 0 {
+        ind -= 1;
 
-                // Release the block to the OS.
-                if let Err(block) = brk::lock().release(block) {
-                    // It failed, put the block back.
-                    // TODO: This can be done faster.
-                    self.push(block);
-                }
+        let cold = local.blocks().get(ind).map_or(false, |x| !x.is_empty() && !policy.is_hot(x));
 
-                // Note that this block is the only block next to the program break, due to the
-                // segments being as long as possible. For that reason, repeating to push and
-                // release would fail.
-            } else {
-                /// Logging...
-                log!(WARNING, "Memtrimming for the global allocator failed.");
+        if cold {
+            let block = local.remove_at(ind).mark_free();
+            global.free_prepared(block);
 
-                // Push the block back.
-                // TODO: This can be done faster.
-                self.push(block);
-            }
+            // Memtrim 'till we won't memtrim anymore.
+            if local.total_bytes() < stop { return; }
         }
     }
+
+    while let Some(block) = local.pop() {
+        // Pop'n'free.
+        global.free_prepared(block);
+
+        // Memtrim 'till we won't memtrim anymore.
+        if local.total_bytes() < stop { break; }
+    }
+}
+
+/// Notify the pressure callbacks of the outcome of acquiring `size` bytes from the memory source.
+///
+/// Only failures of the source itself are reported, not the heap limit being exceeded or malformed
+/// requests.
+fn notify_source<T>(res: &Result<T, AllocError>, size: usize) {
+    match *res {
+        Ok(_) => pressure::source_succeeded(),
+        Err(AllocError::OutOfMemory) => pressure::source_failed(size),
+        Err(_) => (),
+    }
+}
+
+/// Acquire `size` bytes right after `end` from the program break.
+///
+/// This only succeeds if `end` is the program break. The BRK lock is taken, so the global allocator
+/// must be locked by the caller.
+fn extend_brk(end: &Block, size: usize) -> Result<Block, AllocError> {
+    let mut brk = brk::lock();
+
+    // Not being at the program break is no failure of the memory source.
+    if brk.current_brk() != Pointer::from(end.empty_left()) {
+        return Err(AllocError::OutOfMemory);
+    }
+
+    let res = brk.extend(end, size);
+    notify_source(&res, size);
+
+    res
 }
 
 /// A local allocator.
@@ -201,6 +349,11 @@ impl Allocator for GlobalAllocator {
 pub struct LocalAllocator {
     // The inner bookkeeper.
     inner: Bookkeeper,
+    /// The memtrim policy.
+    policy: TrimPolicy,
+    /// The quarantine of freed blocks.
+    #[cfg(feature = "quarantine")]
+    quarantine: Quarantine,
 }
 
 #[cfg(feature = "tls")]
@@ -212,6 +365,10 @@ impl LocalAllocator {
         ///
         /// This will simply free everything to the global allocator.
         extern fn dtor(alloc: &ThreadLocalAllocator) {
+            // Mark the thread as being inside the allocator, such that the corruption handler, the
+            // log target or the debugger can't deadlock on the global allocator.
+            emergency::enter();
+
             /// Logging...
             log!(NOTE, "Deinitializing and freeing the local allocator.");
 
@@ -222,15 +379,34 @@ impl LocalAllocator {
             // a state is in place, all allocation calls will be redirected to the global allocator,
             // which is of course still usable at this moment.
             let alloc = alloc.replace(None).expect("Thread-local allocator is already freed.");
+            #[cfg_attr(not(feature = "quarantine"), allow(unused_mut))]
+            let mut alloc = alloc.into_inner();
 
-            // Lock the global allocator.
-            let mut global_alloc = GLOBAL_ALLOCATOR.lock();
-            let global_alloc = global_alloc.get();
+            {
+                // Lock the global allocator.
+                let mut global_alloc = GLOBAL_ALLOCATOR.lock();
+                let global_alloc = global_alloc.get();
 
-            // TODO: we know this is sorted, so we could abuse that fact to faster insertion in the
-            // global allocator.
+                // Release the quarantined blocks.
+                #[cfg(feature = "quarantine")]
+                while let Some(block) = alloc.quarantine.pop() {
+                    release(global_alloc, block);
+                }
 
-            alloc.into_inner().inner.for_each(move |block| global_alloc.free(block));
+                // TODO: we know this is sorted, so we could abuse that fact to faster insertion in
+                // the global allocator.
+                while let Some(block) = alloc.inner.pop() {
+                    global_alloc.free_prepared(block);
+                }
+
+                // Free the segment holding the (now empty) pool.
+                alloc.inner.for_each(move |block| global_alloc.free(block));
+            }
+
+            emergency::leave();
+
+            // Free the buffers, which were freed from inside the allocator.
+            flush_deferred();
         }
 
         /// Logging...
@@ -248,10 +424,51 @@ impl LocalAllocator {
             // Register the thread destructor on the current thread.
             THREAD_ALLOCATOR.register_thread_destructor(dtor);
 
-            LocalAllocator {
+            // TODO: When added use expr field attributes.
+            #[cfg(feature = "quarantine")]
+            let res = LocalAllocator {
+                inner: Bookkeeper::new(Vec::from_raw_parts(initial_segment, 0)),
+                policy: TrimPolicy::new(),
+                quarantine: Quarantine::new(),
+            };
+            #[cfg(not(feature = "quarantine"))]
+            let res = LocalAllocator {
                 inner: Bookkeeper::new(Vec::from_raw_parts(initial_segment, 0)),
+                policy: TrimPolicy::new(),
+            };
+
+            res
+        }
+    }
+
+    /// Put a block freed by the user in quarantine.
+    #[cfg(feature = "quarantine")]
+    fn quarantine_block(&mut self, block: Block) {
+        // Quarantining a block twice would insert it twice later, so in debug and hardened
+        // builds, we check the quarantine (the pool is checked by `free_bound`).
+        if cfg!(debug_assertions) || cfg!(feature = "hardened") {
+            if let Some(quarantined) = self.quarantine.find_overlap(&block) {
+                fail::corruption(Corruption::OverlappingFree {
+                    ptr: *Pointer::from(block.empty_left()),
+                    size: block.size(),
+                    free_ptr: *Pointer::from(quarantined.empty_left()),
+                    free_size: quarantined.size(),
+                });
+
+                // The handler returned, so we leak the block instead of corrupting the pool.
+                return;
             }
         }
+
+        // Put the block in quarantine, evicting the oldest block if there is no room.
+        if let Some(block) = self.quarantine.push(block) {
+            release(self, block);
+        }
+
+        // Evict blocks until the quarantine is within its budget.
+        while let Some(block) = self.quarantine.pop_excess() {
+            release(self, block);
+        }
     }
 }
 
@@ -261,46 +478,352 @@ derive_deref!(LocalAllocator, Bookkeeper);
 #[cfg(feature = "tls")]
 impl Allocator for LocalAllocator {
     #[inline]
-    fn alloc_fresh(&mut self, size: usize, align: usize) -> Block {
+    fn is_global(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    fn alloc_fresh(&mut self, size: usize, align: usize) -> Result<Block, AllocError> {
         // Get the block from the global allocator. Please note that we cannot canonicalize `size`,
         // due to freeing excessive blocks would change the order.
-        GLOBAL_ALLOCATOR.lock().get().alloc(size, align)
+        GLOBAL_ALLOCATOR.lock().get().try_alloc(size, align)
+    }
+
+    #[inline]
+    fn extend_fresh(&mut self, end: &Block, size: usize) -> Result<Block, AllocError> {
+        // Lock the global allocator first to maintain the lock order.
+        let _global_alloc = GLOBAL_ALLOCATOR.lock();
+
+        extend_brk(end, size)
     }
 
     #[inline]
     fn on_new_memory(&mut self) {
         // The idea is to free memory to the global allocator to unify small stubs and avoid
-        // fragmentation and thread accumulation.
-        if self.total_bytes() < config::FRAGMENTATION_SCALE * self.len()
-           || self.total_bytes() > config::LOCAL_MEMTRIM_LIMIT {
+        // fragmentation and thread accumulation. The limit adapts to the allocation volume of the
+        // thread (see `TrimPolicy`).
+        if self.total_bytes() < config::get(Param::FragmentationScale).saturating_mul(self.len())
+           || self.total_bytes() > self.policy.limit() {
             // Log stuff.
             log!(NOTE, "Memtrimming the local allocator.");
 
+            let stop = self.policy.stop();
+
+            // The pool might be sparse after this.
+            self.schedule_compaction();
+
             // Lock the global allocator.
             let mut global_alloc = GLOBAL_ALLOCATOR.lock();
-            let global_alloc = global_alloc.get();
 
-            while let Some(block) = self.pop() {
-                // Pop'n'free.
-                global_alloc.free(block);
+            // The policy is copied, as the allocator is borrowed mutably.
+            let policy = self.policy.clone();
+            trim_local(self, &policy, global_alloc.get(), stop);
+        }
+    }
 
-                // Memtrim 'till we won't memtrim anymore.
-                if self.total_bytes() < config::LOCAL_MEMTRIM_STOP { break; }
-            }
+    #[inline]
+    fn on_alloc(&mut self, block: &Block, reused: bool) {
+        self.policy.on_alloc(block, reused);
+    }
+
+    fn free_user(&mut self, block: Block) {
+        // Frees advance the memtrim policy as well.
+        self.policy.on_free();
+
+        #[cfg(feature = "quarantine")]
+        self.quarantine_block(block);
+        #[cfg(not(feature = "quarantine"))]
+        self.free(block);
+    }
+}
+
+/// Release a block from the quarantine to some allocator.
+///
+/// The block is checked for writes made during the quarantine first.
+#[cfg(feature = "quarantine")]
+fn release<A: Allocator>(alloc: &mut A, block: Block) {
+    // The debugger was told that the block is freed, so we make it readable first.
+    let block = block.mark_defined();
+
+    // Make sure that the block wasn't written to during the quarantine.
+    if let Err(offset) = block.check_poison() {
+        fail::corruption(Corruption::UseAfterFree {
+            ptr: *Pointer::from(block.empty_left()),
+            offset: offset,
+        });
+    }
+
+    alloc.free(block);
+}
+
+/// Validate that a buffer is owned by ralloc.
+///
+/// When compiled with `validate`, buffers which are not contained in the memory acquired by ralloc
+/// are reported to the heap corruption handler, after which `false` is returned. Otherwise, this
+/// always returns `true`.
+///
+/// Buffers from the emergency arena are valid as well.
+#[inline]
+#[cfg_attr(not(feature = "validate"), allow(unused_variables))]
+fn validate(ptr: *mut u8, size: usize) -> bool {
+    #[cfg(feature = "validate")]
+    {
+        if emergency::owns(ptr) { return true; }
+
+        if let Err(corruption) = owned::validate(ptr, size) {
+            fail::corruption(corruption);
+
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Allocate a block from the emergency arena.
+///
+/// This serves allocations made from inside the allocator, which can't be served by the allocator
+/// in use (see the `emergency` module).
+#[cold]
+fn emergency_alloc(size: usize, align: usize) -> Result<Block, OomInfo> {
+    // Logging...
+    log!(WARNING, "Allocating {} bytes from inside the allocator. Using the emergency arena.", size);
+
+    match emergency::alloc(size, align) {
+        Some(ptr) => {
+            // Tell the debugger.
+            #[cfg(feature = "debugger")]
+            ::shim::debug::malloclike(ptr, size, false);
+
+            Ok(unsafe {
+                // The buffer was just allocated from the arena with this size.
+                Block::from_raw_parts(Pointer::new(ptr), size)
+            })
+        },
+        None => {
+            log!(ERROR, "The emergency arena is exhausted.");
+
+            Err(OomInfo::new(size, align, AllocError::OutOfMemory, false))
+        },
+    }
+}
+
+/// Move a buffer to a new block.
+///
+/// This reallocates buffers, which cannot be reallocated by the bookkeepers, namely buffers from
+/// the emergency arena, and buffers reallocated from inside the allocator.
+///
+/// # Safety
+///
+/// See [`realloc`](./fn.realloc.html).
+#[cold]
+unsafe fn move_block(ptr: *mut u8, old_size: usize, size: usize, align: usize)
+    -> Result<Block, OomInfo> {
+    let mut block = alloc_block(size, align)?;
+
+    // Copy the content, and free the old buffer (if inside the allocator, this is deferred).
+    Block::from_raw_parts(Pointer::new(ptr), cmp::min(old_size, size)).copy_to(&mut block);
+    free(ptr, old_size);
+
+    Ok(block)
+}
+
+/// Free the buffers, whose frees were deferred, since they were made from inside the allocator.
+///
+/// This does nothing, while the current thread is still inside the allocator (i.e. after a nested
+/// call), since the frees would be deferred again.
+#[inline]
+fn flush_deferred() {
+    if emergency::entered() { return; }
+
+    while let Some((ptr, size)) = emergency::pop_deferred() {
+        unsafe {
+            // The buffer was passed to `free` originally.
+            free(ptr, size);
         }
     }
 }
 
+/// Tell the debugger about a reallocated buffer.
+///
+/// If the buffer moved, the new buffer is reported as allocated (keeping the copied content
+/// defined), and the old one as freed. Otherwise, it is reported as resized inplace.
+#[cfg(feature = "debugger")]
+fn debug_realloc(ptr: *mut u8, old_size: usize, block: &Block) {
+    let new = *Pointer::from(block.empty_left());
+
+    if new == ptr {
+        ::shim::debug::resize_inplace(ptr, old_size, block.size());
+    } else {
+        ::shim::debug::malloclike(new, block.size(), false);
+        ::shim::debug::mark_defined(new, cmp::min(old_size, block.size()));
+        ::shim::debug::freelike(ptr);
+    }
+}
+
+/// Allocate a block, charging it to the current thread.
+///
+/// On failure, the thread is refunded, and the error is described for the OOM handlers.
+fn alloc_block(size: usize, align: usize) -> Result<Block, OomInfo> {
+    // The allocator is in use by the current thread, so nested calls are served by the emergency
+    // arena.
+    if emergency::entered() {
+        return emergency_alloc(size, align);
+    }
+
+    // Verify the heap periodically.
+    verify::tick();
+
+    // The thread limit is exceeded in the thread-local accounting, thus not in the global
+    // allocator.
+    limit::charge(size).map_err(|err| OomInfo::new(size, align, err, false))?;
+
+    let attempt = || get_allocator!(|alloc| {
+        alloc.try_alloc(size, align).map_err(|err| OomInfo::new(size, align, err, alloc.is_global()))
+    });
+
+    // Notify the pressure callbacks outside the allocator. If the memory source failed, the
+    // callbacks might have freed memory, so we retry.
+    let failures = pressure::failures();
+    let mut res = attempt();
+    if pressure::dispatch(failures) && res.is_err() {
+        res = attempt();
+    }
+
+    match res {
+        Ok(block) => {
+            stats::allocated(size);
+
+            // Tell the debugger.
+            #[cfg(feature = "debugger")]
+            ::shim::debug::malloclike(*Pointer::from(block.empty_left()), size, false);
+
+            Ok(block)
+        },
+        Err(info) => {
+            limit::refund(size);
+
+            Err(info)
+        },
+    }
+}
+
+/// Reallocate a block, charging the growth to the current thread.
+///
+/// On failure, the old buffer is left intact, the thread is refunded, and the error is described
+/// for the OOM handlers.
+///
+/// # Safety
+///
+/// See [`realloc`](./fn.realloc.html).
+unsafe fn realloc_block(ptr: *mut u8, old_size: usize, size: usize, align: usize)
+    -> Result<Block, OomInfo> {
+    if emergency::entered() || emergency::owns(ptr) {
+        return move_block(ptr, old_size, size, align);
+    }
+
+    // Verify the heap periodically.
+    verify::tick();
+
+    // Charge the growth. If the buffer shrinks, nothing is charged.
+    limit::charge(size.saturating_sub(old_size)).map_err(|err| OomInfo::new(size, align, err, false))?;
+
+    let attempt = || get_allocator!(|alloc| {
+        alloc.try_realloc(
+            Block::from_raw_parts(Pointer::new(ptr), old_size),
+            size,
+            align
+        ).map_err(|err| OomInfo::new(size, align, err, alloc.is_global()))
+    });
+
+    // Notify the pressure callbacks outside the allocator. If the memory source failed, the
+    // callbacks might have freed memory, so we retry (failed reallocations leave the old buffer
+    // intact).
+    let failures = pressure::failures();
+    let mut res = attempt();
+    if pressure::dispatch(failures) && res.is_err() {
+        res = attempt();
+    }
+
+    match res {
+        Ok(block) => {
+            limit::refund(old_size.saturating_sub(size));
+            stats::reallocated(old_size, size);
+
+            // Tell the debugger.
+            #[cfg(feature = "debugger")]
+            debug_realloc(ptr, old_size, &block);
+
+            Ok(block)
+        },
+        Err(info) => {
+            limit::refund(size.saturating_sub(old_size));
+
+            Err(info)
+        },
+    }
+}
+
 /// Allocate a block of memory.
 ///
 /// # Errors
 ///
-/// The OOM handler handles out-of-memory conditions.
+/// The OOM handler handles out-of-memory conditions (including exceeded limits). If it asks for a
+/// retry, the allocation is retried.
 #[inline]
 pub fn alloc(size: usize, align: usize) -> *mut u8 {
     log!(CALL, "Allocating buffer of size {} (align {}).", size, align);
 
-    get_allocator!(|alloc| *Pointer::from(alloc.alloc(size, align)))
+    // Call the OOM handlers outside the allocator, such that they can free memory.
+    let block = alloc_block(size, align).unwrap_or_else(|info| {
+        fail::oom(info, || alloc_block(size, align).map_err(|info| info.error))
+    });
+
+    *Pointer::from(block)
+}
+
+/// Try to allocate a block of memory.
+///
+/// In contrast to [`alloc`](./fn.alloc.html), this does not call the OOM handler on failure, but
+/// returns an error instead. This allows for gracefully handling e.g. huge requests.
+///
+/// # Errors
+///
+/// If the memory cannot be acquired, `AllocError::OutOfMemory` is returned. If the size
+/// overflows, `AllocError::SizeOverflow` is returned. If the alignment is zero,
+/// `AllocError::InvalidAlignment` is returned. If the heap limit or the thread limit would be
+/// exceeded, `AllocError::LimitExceeded` is returned.
+#[inline]
+pub fn try_alloc(size: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+    log!(CALL, "Trying to allocate buffer of size {} (align {}).", size, align);
+
+    if align == 0 {
+        return Err(AllocError::InvalidAlignment);
+    }
+
+    alloc_block(size, align).map(|block| unsafe {
+        // `Pointer` is non-null as well.
+        NonNull::new_unchecked(*Pointer::from(block))
+    }).map_err(|info| info.error)
+}
+
+/// Try to allocate a zeroed block of memory.
+///
+/// See [`try_alloc`](./fn.try_alloc.html) for more information.
+///
+/// # Errors
+///
+/// The same errors as `try_alloc` are returned.
+#[inline]
+pub fn try_alloc_zeroed(size: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+    let res = try_alloc(size, align)?;
+
+    unsafe {
+        // The buffer was just allocated with this size.
+        ptr::write_bytes(res.as_ptr(), 0, size);
+    }
+
+    Ok(res)
 }
 
 /// Free a buffer.
@@ -313,6 +836,9 @@ pub fn alloc(size: usize, align: usize) -> *mut u8 {
 /// You should only allocate buffers allocated through `ralloc`. Anything else is considered
 /// invalid.
 ///
+/// When compiled with `validate`, buffers lying outside the memory owned by ralloc are rejected,
+/// and reported to the heap corruption handler.
+///
 /// # Errors
 ///
 /// The OOM handler handles out-of-memory conditions.
@@ -327,7 +853,40 @@ pub fn alloc(size: usize, align: usize) -> *mut u8 {
 pub unsafe fn free(ptr: *mut u8, size: usize) {
     log!(CALL, "Freeing buffer of size {}.", size);
 
-    get_allocator!(|alloc| alloc.free(Block::from_raw_parts(Pointer::new(ptr), size)))
+    // Buffers from the emergency arena are never reused.
+    if emergency::owns(ptr) {
+        // Tell the debugger.
+        #[cfg(feature = "debugger")]
+        ::shim::debug::freelike(ptr);
+
+        return;
+    }
+
+    // The allocator is in use by the current thread, so the buffer is freed, when it is left.
+    if emergency::entered() {
+        if !emergency::defer_free(ptr, size) {
+            log!(ERROR, "Too many frees from inside the allocator. Leaking buffer of size {}.", size);
+        }
+
+        return;
+    }
+
+    // Reject buffers not owned by ralloc.
+    if !validate(ptr, size) { return; }
+
+    // Verify the heap periodically.
+    verify::tick();
+
+    stats::freed(size);
+    limit::refund(size);
+
+    get_allocator!(|alloc| {
+        alloc.free_user(Block::from_raw_parts(Pointer::new(ptr), size));
+
+        // Tell the debugger, now that the allocator is done writing to the buffer.
+        #[cfg(feature = "debugger")]
+        ::shim::debug::freelike(ptr);
+    })
 }
 
 /// Reallocate memory.
@@ -340,9 +899,14 @@ pub unsafe fn free(ptr: *mut u8, size: usize) {
 /// You should only reallocate buffers allocated through `ralloc`. Anything else is considered
 /// invalid.
 ///
+/// When compiled with `validate`, buffers lying outside the memory owned by ralloc are rejected,
+/// and reported to the heap corruption handler. If the handler returns, a null pointer is
+/// returned.
+///
 /// # Errors
 ///
-/// The OOM handler handles out-of-memory conditions.
+/// The OOM handler handles out-of-memory conditions. If it asks for a retry, the reallocation is
+/// retried.
 ///
 /// # Safety
 ///
@@ -352,13 +916,50 @@ pub unsafe fn free(ptr: *mut u8, size: usize) {
 pub unsafe fn realloc(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> *mut u8 {
     log!(CALL, "Reallocating buffer of size {} to new size {}.", old_size, size);
 
-    get_allocator!(|alloc| {
-        *Pointer::from(alloc.realloc(
-            Block::from_raw_parts(Pointer::new(ptr), old_size),
-            size,
-            align
-        ))
-    })
+    // Reject buffers not owned by ralloc.
+    if !validate(ptr, old_size) { return ptr::null_mut(); }
+
+    // Call the OOM handlers outside the allocator, such that they can free memory. Failed
+    // reallocations leave the old buffer intact, hence it can simply be retried.
+    let block = realloc_block(ptr, old_size, size, align).unwrap_or_else(|info| {
+        fail::oom(info, || realloc_block(ptr, old_size, size, align).map_err(|info| info.error))
+    });
+
+    *Pointer::from(block)
+}
+
+/// Try to reallocate memory.
+///
+/// In contrast to [`realloc`](./fn.realloc.html), this does not call the OOM handler on failure,
+/// but returns an error instead. The old buffer is then left intact.
+///
+/// # Errors
+///
+/// The same errors as `try_alloc` are returned. Furthermore, when compiled with `validate`,
+/// buffers rejected by the heap corruption handler yield `AllocError::InvalidBuffer`.
+///
+/// # Safety
+///
+/// Due to being able to potentially memcpy an arbitrary buffer, as well as shrinking a buffer,
+/// this is marked unsafe.
+#[inline]
+pub unsafe fn try_realloc(ptr: *mut u8, old_size: usize, size: usize, align: usize)
+    -> Result<NonNull<u8>, AllocError> {
+    log!(CALL, "Trying to reallocate buffer of size {} to new size {}.", old_size, size);
+
+    if align == 0 {
+        return Err(AllocError::InvalidAlignment);
+    }
+
+    // Reject buffers not owned by ralloc.
+    if !validate(ptr, old_size) {
+        return Err(AllocError::InvalidBuffer);
+    }
+
+    realloc_block(ptr, old_size, size, align).map(|block| {
+        // `Pointer` is non-null as well.
+        NonNull::new_unchecked(*Pointer::from(block))
+    }).map_err(|info| info.error)
 }
 
 /// Try to reallocate the buffer _inplace_.
@@ -367,6 +968,9 @@ pub unsafe fn realloc(ptr: *mut u8, old_size: usize, size: usize, align: usize)
 ///
 /// This can be used to shrink (truncate) a buffer as well.
 ///
+/// When compiled with `validate`, buffers lying outside the memory owned by ralloc are rejected,
+/// and reported to the heap corruption handler. If the handler returns, this fails.
+///
 /// # Safety
 ///
 /// Due to being able to shrink (and thus free) the buffer, this is marked unsafe.
@@ -374,14 +978,284 @@ pub unsafe fn realloc(ptr: *mut u8, old_size: usize, size: usize, align: usize)
 pub unsafe fn realloc_inplace(ptr: *mut u8, old_size: usize, size: usize) -> Result<(), ()> {
     log!(CALL, "Inplace reallocating buffer of size {} to new size {}.", old_size, size);
 
-    get_allocator!(|alloc| {
-        if alloc.realloc_inplace(
+    // Reject buffers not owned by ralloc.
+    if !validate(ptr, old_size) { return Err(()); }
+
+    // Buffers from the emergency arena are fixed, and nested calls can't use the allocator.
+    if emergency::entered() || emergency::owns(ptr) { return Err(()); }
+
+    // Verify the heap periodically.
+    verify::tick();
+
+    // Charge the growth. If the buffer shrinks, nothing is charged.
+    if limit::charge(size.saturating_sub(old_size)).is_err() { return Err(()); }
+
+    let attempt = || get_allocator!(|alloc| {
+        alloc.realloc_inplace(
             Block::from_raw_parts(Pointer::new(ptr), old_size),
             size
-        ).is_ok() {
-            Ok(())
-        } else {
-            Err(())
+        ).is_ok()
+    });
+
+    // Notify the pressure callbacks outside the allocator (growing the top of the heap acquires
+    // memory from the source). If the source failed, we retry like `realloc` does.
+    let failures = pressure::failures();
+    let mut res = attempt();
+    if pressure::dispatch(failures) && !res {
+        res = attempt();
+    }
+
+    if res {
+        limit::refund(old_size.saturating_sub(size));
+        stats::reallocated(old_size, size);
+
+        // Tell the debugger.
+        #[cfg(feature = "debugger")]
+        ::shim::debug::resize_inplace(ptr, old_size, size);
+
+        Ok(())
+    } else {
+        limit::refund(size.saturating_sub(old_size));
+
+        Err(())
+    }
+}
+
+/// Check the consistency of the block pools of the current thread and the global allocator.
+///
+/// This is NOOP in release mode.
+#[cfg(feature = "fault_injection")]
+pub fn check() {
+    get_allocator!(|alloc| alloc.check());
+
+    GLOBAL_ALLOCATOR.lock().get().check();
+}
+
+/// Call a function on the bookkeepers of the current thread and the global allocator.
+///
+/// The second argument of the function tells if the bookkeeper is the global one.
+pub fn for_each_bookkeeper<F: FnMut(&Bookkeeper, bool)>(mut f: F) {
+    #[cfg(feature = "tls")]
+    {
+        get_allocator!(|alloc| f(alloc, false));
+    }
+
+    f(GLOBAL_ALLOCATOR.lock().get(), true);
+}
+
+/// Compact the block pools of the current thread and the global allocator.
+///
+/// This removes the empty entries from the pools, and moves pools using only a small part of
+/// their capacity to smaller segments (see `POOL_SHRINK_FACTOR`), freeing the old ones. The number
+/// of bytes of the freed segments is returned.
+pub fn compact() -> usize {
+    log!(CALL, "Compacting the block pools.");
+
+    #[cfg(feature = "tls")]
+    let local = get_allocator!(|alloc| alloc.compact());
+    #[cfg(not(feature = "tls"))]
+    let local = 0;
+
+    emergency::enter();
+    let global = GLOBAL_ALLOCATOR.lock().get().compact();
+    emergency::leave();
+
+    // Free the buffers, which were freed from inside the allocator.
+    flush_deferred();
+
+    local + global
+}
+
+/// Acquire the global allocator lock before forking.
+///
+/// The lock is kept until `fork_unlock` is called.
+pub fn fork_lock() {
+    mem::forget(GLOBAL_ALLOCATOR.lock());
+}
+
+/// Release the global allocator lock after forking.
+///
+/// # Safety
+///
+/// This must be paired with a preceding call to `fork_lock`.
+pub unsafe fn fork_unlock() {
+    GLOBAL_ALLOCATOR.force_unlock();
+}
+
+/// The amount of memory released by a trim.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrimStats {
+    /// The number of bytes released to the OS.
+    pub to_os: usize,
+    /// The number of bytes released to the global allocator.
+    pub to_global: usize,
+    /// The number of bytes of pool segments freed by compacting the global allocator.
+    ///
+    /// These are released to the OS as well, if they end up on the top of the heap (and are then
+    /// included in `to_os`).
+    pub compacted: usize,
+    /// The number of bytes of free pages advised to the OS as unused.
+    ///
+    /// These are not released (they stay part of the heap), but their physical memory can be
+    /// reclaimed. Pages advised by earlier trims are advised (and counted) again.
+    pub purged: usize,
+}
+
+/// Drain the local allocator of the current thread into the global allocator.
+///
+/// This is useful at known-idle points, instead of waiting for the memtrim heuristics. Blocks held
+/// in quarantine are released as well. If the global allocator memtrims as a result, the bytes
+/// released to the OS are reported too.
+pub fn trim_thread() -> TrimStats {
+    log!(CALL, "Trimming the local allocator.");
+
+    #[cfg_attr(not(feature = "tls"), allow(unused_mut))]
+    let mut res = TrimStats::default();
+
+    #[cfg(feature = "tls")]
+    THREAD_ALLOCATOR.with(|thread_alloc| {
+        // Move the allocator out, like `get_allocator!` does. If it is deinitialized, there is
+        // nothing to drain.
+        if let Some(mut thread_alloc_original) = thread_alloc.replace(None) {
+            emergency::enter();
+
+            // Don't initialize the allocator just to drain it.
+            if let Some(alloc) = thread_alloc_original.get_initialized() {
+                // Lock the global allocator.
+                let mut global_alloc = GLOBAL_ALLOCATOR.lock();
+                let global_alloc = global_alloc.get();
+
+                // The heap can only shrink through the global allocator, whose lock we hold.
+                let heap_size = stats::stats().heap_size;
+
+                // Release the quarantined blocks.
+                #[cfg(feature = "quarantine")]
+                while let Some(block) = alloc.quarantine.pop() {
+                    res.to_global += block.size();
+                    release(global_alloc, block);
+                }
+
+                // Pop'n'free.
+                while let Some(block) = alloc.pop() {
+                    res.to_global += block.size();
+                    global_alloc.free_prepared(block);
+                }
+
+                // The pool is empty now. It is compacted on the next allocation, since the global
+                // allocator (which serves the new segment) is locked.
+                alloc.schedule_compaction();
+
+                res.to_os = heap_size.saturating_sub(stats::stats().heap_size);
+            }
+
+            // Put back the original allocator.
+            thread_alloc.replace(Some(thread_alloc_original));
+            emergency::leave();
         }
-    })
+    });
+
+    // Free the buffers, which were freed from inside the allocator.
+    flush_deferred();
+
+    res
+}
+
+/// Trim the allocator.
+///
+/// This drains the local allocator of the current thread (see
+/// [`trim_thread`](./fn.trim_thread.html)), compacts the pool of the global allocator, releases
+/// the top of the program break to the OS regardless of the memtrim limits, and purges the pages
+/// of the free blocks in the global allocator (if supported by the platform and not compiled with
+/// `poison`).
+pub fn trim() -> TrimStats {
+    log!(CALL, "Trimming the allocator.");
+
+    let mut res = trim_thread();
+
+    emergency::enter();
+
+    {
+        // Lock the global allocator.
+        let mut global_alloc = GLOBAL_ALLOCATOR.lock();
+        let global_alloc = global_alloc.get();
+
+        // Compact the pool first, such that the old pool segment can be released as well.
+        res.compacted = global_alloc.compact();
+
+        // Release the top of the program break.
+        res.to_os += global_alloc.memtrim(0);
+
+        // Purge the pages of the free blocks.
+        res.purged = global_alloc.blocks().iter().map(brk::purge).sum();
+    }
+
+    emergency::leave();
+
+    // Free the buffers, which were freed from inside the allocator.
+    flush_deferred();
+
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "tls")]
+    use std::vec::Vec as StdVec;
+
+    #[cfg(feature = "tls")]
+    use sim::{SimAllocator, Source};
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_trim_local() {
+        let mut local_buf = [0u64; 8192];
+        let mut global_buf = [0u64; 8192];
+        let mut local = SimAllocator::new(unsafe {
+            Source::new(local_buf.as_mut_ptr() as *mut u8, local_buf.len() * 8)
+        });
+        let mut global = SimAllocator::new(unsafe {
+            Source::new(global_buf.as_mut_ptr() as *mut u8, global_buf.len() * 8)
+        });
+        let mut policy = TrimPolicy::new();
+
+        // Leave holes between live blocks.
+        let mut live = StdVec::new();
+        for n in 0..12 {
+            let block = local.alloc(256, 8);
+            if n % 2 == 0 {
+                local.free(block);
+            } else {
+                live.push(block);
+            }
+        }
+
+        // Reuse one of the holes.
+        let (hot, hot_size) = {
+            let block = local.blocks().iter().find(|x| !x.is_empty()).unwrap();
+            policy.on_alloc(block, true);
+
+            (*Pointer::from(block.empty_left()), block.size())
+        };
+
+        let local_bytes = local.total_bytes();
+        let global_bytes = global.total_bytes();
+        trim_local(&mut local, &policy, &mut global, hot_size + 1);
+
+        // Only the hot block is left, and the rest went to the global allocator (whose pool might
+        // have acquired memory as well).
+        let left: StdVec<_> = local.blocks().iter().filter(|x| !x.is_empty()).collect();
+        assert_eq!(left.len(), 1);
+        assert_eq!(*Pointer::from(left[0].empty_left()), hot);
+        assert_eq!(local.total_bytes(), hot_size);
+        assert!(global.total_bytes() >= global_bytes + local_bytes - hot_size);
+
+        assert_eq!(local.verify(), Ok(()));
+        assert_eq!(global.verify(), Ok(()));
+
+        for block in live {
+            local.free(block);
+        }
+    }
 }
@@ -7,7 +7,9 @@
 
 use prelude::*;
 
-use core::{ptr, cmp, mem, fmt};
+use core::{ptr, cmp, mem, fmt, slice, intrinsics};
+
+use shim::config;
 
 /// A contiguous memory block.
 ///
@@ -135,8 +137,6 @@ impl Block {
 
     /// Volatile zero this memory if the `security` feature is set.
     pub fn sec_zero(&mut self) {
-        use core::intrinsics;
-
         if cfg!(feature = "security") {
             log!(INTERNAL, "Zeroing {:?}", *self);
 
@@ -150,6 +150,57 @@ impl Block {
         }
     }
 
+    /// Fill this block with the free poison pattern if the `poison` feature is set.
+    ///
+    /// The pattern can later be verified through [`check_poison`](#method.check_poison) to detect
+    /// writes to the block while it was free.
+    pub fn poison(&mut self) {
+        if cfg!(feature = "poison") {
+            log!(INTERNAL, "Poisoning {:?}", *self);
+
+            unsafe {
+                // Since the memory of the block is inaccessible (read-wise), overwriting it is
+                // fully safe.
+                intrinsics::volatile_set_memory(*self.ptr, config::FREE_POISON, self.size);
+            }
+        }
+    }
+
+    /// Verify that this block is intact with the free poison pattern.
+    ///
+    /// If the `poison` feature is set and the block has been modified, the offset of the first
+    /// modified byte is returned as error.
+    pub fn check_poison(&self) -> Result<(), usize> {
+        if cfg!(feature = "poison") {
+            let buf = unsafe {
+                // The block owns the memory and is valid for reads.
+                slice::from_raw_parts(*self.ptr as *const u8, self.size)
+            };
+
+            if let Some(offset) = buf.iter().position(|&x| x != config::FREE_POISON) {
+                return Err(offset);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fill this block with the uninitialized poison pattern if the `poison` feature is set.
+    ///
+    /// This does nothing, if no such pattern is configured.
+    pub fn poison_uninitialized(&mut self) {
+        if cfg!(feature = "poison") {
+            if let Some(pattern) = config::UNINIT_POISON {
+                log!(INTERNAL, "Poisoning {:?} as uninitialized", *self);
+
+                unsafe {
+                    // The block owns the memory, hence overwriting it is safe.
+                    intrinsics::volatile_set_memory(*self.ptr, pattern, self.size);
+                }
+            }
+        }
+    }
+
     /// "Pop" this block.
     ///
     /// This marks it as free, and returns the old value.
@@ -158,6 +209,17 @@ impl Block {
         unborrow!(mem::replace(self, Block::empty(self.ptr.clone())))
     }
 
+    /// Does this block overlap with the given other block?
+    ///
+    /// Empty blocks never overlap with anything.
+    #[inline]
+    pub fn overlaps(&self, other: &Block) -> bool {
+        // This won't overflow due to the ends being bounded by the address space.
+        !self.is_empty() && !other.is_empty()
+            && (*self.ptr as usize) < *other.ptr as usize + other.size
+            && (*other.ptr as usize) < *self.ptr as usize + self.size
+    }
+
     /// Is this block placed left to the given other block?
     #[inline]
     pub fn left_to(&self, to: &Block) -> bool {
@@ -193,6 +255,25 @@ impl Block {
         )
     }
 
+    /// Calculate the aligner of this block.
+    ///
+    /// The aligner is the smallest size required as precursor to align the block to `align`.
+    #[inline]
+    fn aligner(&self, align: usize) -> usize {
+        (align - *self.ptr as usize % align) % align
+        //                                  ^^^^^^^^
+        // To avoid wasting space on the case where the block is already aligned, we calculate it
+        // modulo `align`.
+    }
+
+    /// Can a segment of size `size` aligned to `align` be carved out of this block?
+    #[inline]
+    pub fn fits(&self, size: usize, align: usize) -> bool {
+        let aligner = self.aligner(align);
+
+        aligner < self.size && self.size - aligner >= size
+    }
+
     /// Split this block, such that the second block is aligned to `align`.
     ///
     /// Returns an `None` holding the intact block if `align` is out of bounds.
@@ -205,12 +286,7 @@ impl Block {
         // TODO: This functions suffers from external fragmentation. Leaving bigger segments might
         // increase performance.
 
-        // Calculate the aligner, which defines the smallest size required as precursor to align
-        // the block to `align`.
-        let aligner = (align - *self.ptr as usize % align) % align;
-        //                                                 ^^^^^^^^
-        // To avoid wasting space on the case where the block is already aligned, we calculate it
-        // modulo `align`.
+        let aligner = self.aligner(align);
 
         // Bound check.
         if aligner < self.size {
@@ -253,13 +329,26 @@ impl Block {
         self
     }
 
+    /// Mark this block defined to the debugger.
+    ///
+    /// This makes free blocks readable (e.g. for checking their poison pattern) without the
+    /// debugger reporting reads of inaccessible or undefined memory.
+    #[inline]
+    pub fn mark_defined(self) -> Block {
+        #[cfg(feature = "debugger")]
+        ::shim::debug::mark_defined(*self.ptr as *const u8, self.size);
+
+        self
+    }
+
     /// Mark this block uninitialized to the debugger.
     ///
-    /// To detect use-after-free, the allocator need to mark
+    /// To detect use-after-free, the allocator need to mark free blocks inaccessible, and this
+    /// makes them accessible again (but their content undefined) when they are handed out.
     #[inline]
     pub fn mark_uninitialized(self) -> Block {
         #[cfg(feature = "debugger")]
-        ::shim::debug::mark_unintialized(*self.ptr as *const u8, self.size);
+        ::shim::debug::mark_uninitialized(*self.ptr as *const u8, self.size);
 
         self
     }
@@ -326,6 +415,20 @@ mod test {
         assert_eq!(*Pointer::from(lorem) as usize + 5, *Pointer::from(rest) as usize);
     }
 
+    #[test]
+    fn test_fits() {
+        let arr = [0u64; 4];
+        let block = unsafe {
+            Block::from_raw_parts(Pointer::new(&arr[0] as *const u64 as *mut u8), 32)
+        };
+        let (_, block) = block.split(1);
+
+        assert!(block.fits(31, 1));
+        assert!(!block.fits(32, 1));
+        assert!(block.fits(24, 8));
+        assert!(!block.fits(25, 8));
+    }
+
     #[test]
     fn test_merge() {
         let arr = b"Lorem ipsum dolor sit amet";
@@ -367,6 +470,45 @@ mod test {
         assert_eq!(arr, [0, 2, 0, 2, 255, 255]);
     }
 
+    #[test]
+    fn test_overlaps() {
+        let arr = b"Lorem ipsum dolor sit amet";
+        let block = unsafe {
+            Block::from_raw_parts(Pointer::new(arr.as_ptr() as *mut u8), arr.len())
+        };
+
+        let (lorem, rest) = block.split(5);
+        assert!(!lorem.overlaps(&rest));
+        assert!(!rest.overlaps(&lorem));
+        assert!(lorem.overlaps(&lorem));
+        assert!(!lorem.overlaps(&lorem.empty_right()));
+
+        let inner = unsafe {
+            Block::from_raw_parts(Pointer::new(arr.as_ptr().offset(3) as *mut u8), 4)
+        };
+        assert!(inner.overlaps(&lorem));
+        assert!(inner.overlaps(&rest));
+        assert!(rest.overlaps(&inner));
+    }
+
+    #[test]
+    #[cfg(feature = "poison")]
+    fn test_poison() {
+        let mut arr = [0u8; 16];
+        let mut block = unsafe {
+            Block::from_raw_parts(Pointer::new(&mut arr[0] as *mut u8), 16)
+        };
+
+        assert_eq!(block.check_poison(), Err(0));
+        block.poison();
+        assert_eq!(block.check_poison(), Ok(()));
+
+        unsafe {
+            *(*Pointer::from(block.empty_left())).offset(5) = 0;
+        }
+        assert_eq!(block.check_poison(), Err(5));
+    }
+
     #[test]
     fn test_empty_lr() {
         let arr = b"Lorem ipsum dolor sit amet";
@@ -3,9 +3,13 @@
 use prelude::*;
 
 use core::ops::Range;
-use core::{ptr, mem, ops};
+use core::{ptr, mem, ops, cmp};
 
-use shim::config;
+use config::{self, Param};
+use shim::config::{POOL_SHRINK_FACTOR, RANDOM_CANDIDATES, RANDOM_MAX_OFFSET};
+
+use fail::{self, Corruption, AllocError, HeapError, OomInfo};
+use random;
 
 /// Elements required _more_ than the length as capacity.
 ///
@@ -20,6 +24,39 @@ use shim::config;
 /// See assumption 4.
 pub const EXTRA_ELEMENTS: usize = 4;
 
+/// Canonicalize a fresh allocation.
+///
+/// This follows the curve of the shim, with the parameters of the runtime configuration.
+#[inline]
+fn extra_fresh(size: usize) -> usize {
+    cmp::max(config::get(Param::FreshMinExtra),
+             cmp::min(size.saturating_mul(config::get(Param::FreshMultiplier)),
+                      config::get(Param::FreshMaxExtra)))
+}
+
+/// Prepare a free block for being handed out.
+///
+/// This makes sure that the block hasn't been written to, while being free (reporting it to the
+/// heap corruption handler otherwise), and fills it with the uninitialized pattern.
+fn reuse(block: Block) -> Block {
+    // Make the block readable to the debugger, such that the check below isn't reported.
+    let mut block = block.mark_defined();
+
+    // Make sure that the block hasn't been written to, while being free.
+    if let Err(offset) = block.check_poison() {
+        fail::corruption(Corruption::UseAfterFree {
+            ptr: *Pointer::from(block.empty_left()),
+            offset: offset,
+        });
+    }
+
+    // Fill it with the uninitialized pattern.
+    block.poison_uninitialized();
+
+    // Mark the block uninitialized to the debugger.
+    block.mark_uninitialized()
+}
+
 #[cfg(feature = "alloc_id")]
 use core::sync::atomic::{self, AtomicUsize};
 /// The bookkeeper ID count.
@@ -62,6 +99,10 @@ pub struct Bookkeeper {
     ///
     // TODO: Find a replacement for this "hack".
     reserving: bool,
+    /// Should the pool be compacted on the next allocation?
+    ///
+    /// The memtrims run while the pool is being modified, so they cannot compact it right away.
+    compact_pending: bool,
     /// The allocator ID.
     ///
     /// This is simply to be able to distinguish allocators in the locks.
@@ -83,6 +124,7 @@ impl Bookkeeper {
             pool: vec,
             total_bytes: 0,
             reserving: false,
+            compact_pending: false,
             // Increment the ID counter to get a brand new ID.
             id: BOOKKEEPER_ID_COUNTER.fetch_add(1, atomic::Ordering::SeqCst),
         };
@@ -91,6 +133,7 @@ impl Bookkeeper {
             pool: vec,
             total_bytes: 0,
             reserving: false,
+            compact_pending: false,
         };
 
         bk_log!(res, "Bookkeeper created.");
@@ -156,6 +199,20 @@ impl Bookkeeper {
         left_ind..right_ind
     }
 
+    /// Find a free block overlapping with `block` in some index bound.
+    ///
+    /// The bound is assumed to be the one returned by `find_bound`, hence only the left neighbor
+    /// and the blocks inside the bound can possibly overlap.
+    fn find_overlap(&self, ind: &Range<usize>, block: &Block) -> Option<&Block> {
+        // Check the left neighbor, which might extend into the block.
+        if ind.start != 0 && self.pool[ind.start - 1].overlaps(block) {
+            return Some(&self.pool[ind.start - 1]);
+        }
+
+        // Check the blocks starting inside the block.
+        self.pool[ind.clone()].iter().find(|x| x.overlaps(block))
+    }
+
     /// Go over every block in the allocator and call some function.
     ///
     /// Technically, this could be done through an iterator, but this, more unidiomatic, way is
@@ -186,6 +243,23 @@ impl Bookkeeper {
         })
     }
 
+    /// Compact the pool on the next allocation.
+    ///
+    /// This is used where the pool cannot be compacted right away (e.g. while inserting).
+    pub fn schedule_compaction(&mut self) {
+        self.compact_pending = true;
+    }
+
+    /// Get the free blocks of the pool.
+    pub fn blocks(&self) -> &[Block] {
+        &self.pool
+    }
+
+    /// Get the capacity of the pool.
+    pub fn capacity(&self) -> usize {
+        self.pool.capacity()
+    }
+
     /// Get the length of the pool.
     pub fn len(&self) -> usize {
         self.pool.len()
@@ -196,60 +270,84 @@ impl Bookkeeper {
         self.total_bytes
     }
 
-    /// Perform consistency checks.
+    /// Verify the consistency of the pool.
     ///
     /// This will check for the following conditions:
     ///
     /// 1. The list is sorted.
     /// 2. No blocks are adjacent.
+    /// 3. Empty blocks are placed at the address of their right neighbor, and are not trailing.
+    /// 4. The sum of the block sizes is `total_bytes`.
+    /// 5. The capacity is at least `EXTRA_ELEMENTS` more than the length.
+    ///
+    /// In contrast to [`check`](#method.check), this runs in release mode too.
+    pub fn verify(&self) -> Result<(), HeapError> {
+        // The total number of bytes.
+        let mut total_bytes = 0;
+        // Reverse iterator over the blocks.
+        let mut it = self.pool.iter().enumerate().rev();
+
+        // Check that the capacity is large enough.
+        if !self.reserving && self.pool.len() + EXTRA_ELEMENTS > self.pool.capacity() {
+            return Err(HeapError::Capacity {
+                len: self.pool.len(),
+                capacity: self.pool.capacity(),
+            });
+        }
+
+        if let Some((n, x)) = it.next() {
+            // Make sure there are no trailing empty blocks.
+            if x.is_empty() {
+                return Err(HeapError::MisplacedEmpty { index: n });
+            }
+
+            total_bytes += x.size();
+
+            let mut next = x;
+            for (n, i) in it {
+                total_bytes += i.size();
+
+                // Check if sorted.
+                if next < i {
+                    return Err(HeapError::Unsorted { index: n });
+                }
+                // Make sure no blocks are adjacent.
+                if i.left_to(next) && !i.is_empty() {
+                    return Err(HeapError::Adjacent { index: n });
+                }
+                // Make sure an empty block has the same address as its right neighbor.
+                if i.is_empty() && i != next {
+                    return Err(HeapError::MisplacedEmpty { index: n });
+                }
+
+                // Set the variable tracking the previous block.
+                next = i;
+            }
+        }
+
+        // Make sure the sum is maintained properly.
+        if total_bytes != self.total_bytes {
+            return Err(HeapError::ByteCount {
+                counted: total_bytes,
+                recorded: self.total_bytes,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Perform consistency checks.
+    ///
+    /// This asserts that [`verify`](#method.verify) succeeds.
     ///
     /// This is NOOP in release mode.
-    fn check(&self) {
+    pub fn check(&self) {
         if cfg!(debug_assertions) {
             // Logging.
             bk_log!(self, "Checking...");
 
-            // The total number of bytes.
-            let mut total_bytes = 0;
-            // Reverse iterator over the blocks.
-            let mut it = self.pool.iter().enumerate().rev();
-
-            // Check that the capacity is large enough.
-            assert!(self.reserving || self.pool.len() + EXTRA_ELEMENTS <= self.pool.capacity(),
-                    "The capacity should be at least {} more than the length of the pool.",
-                    EXTRA_ELEMENTS);
-
-            if let Some((_, x)) = it.next() {
-                // Make sure there are no leading empty blocks.
-                assert!(!x.is_empty(), "The leading block is empty.");
-
-                total_bytes += x.size();
-
-                let mut next = x;
-                for (n, i) in it {
-                    total_bytes += i.size();
-
-                    // Check if sorted.
-                    assert!(next >= i, "The block pool is not sorted at index, {} ({:?} < {:?}).",
-                            n, next, i);
-                    // Make sure no blocks are adjacent.
-                    assert!(!i.left_to(next) || i.is_empty(), "Adjacent blocks at index, {} ({:?} and \
-                            {:?})", n, i, next);
-                    // Make sure an empty block has the same address as its right neighbor.
-                    assert!(!i.is_empty() || i == next, "Empty block not adjacent to right neighbor \
-                            at index {} ({:?} and {:?})", n, i, next);
-
-                    // Set the variable tracking the previous block.
-                    next = i;
-                }
-
-                // Check for trailing empty blocks.
-                assert!(!self.pool.last().unwrap().is_empty(), "Trailing empty blocks.");
-            }
-
-            // Make sure the sum is maintained properly.
-            assert!(total_bytes == self.total_bytes, "The sum is not equal to the 'total_bytes' \
-                    field: {} ≠ {}.", total_bytes, self.total_bytes);
+            let res = self.verify();
+            assert!(res.is_ok(), "The block pool is inconsistent: {}.", res.unwrap_err());
         }
     }
 }
@@ -281,11 +379,47 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
     ///
     /// This is assumed to not modify the order. If some block `b` is associated with index `i`
     /// prior to call of this function, it should be too after it.
-    fn alloc_fresh(&mut self, size: usize, align: usize) -> Block;
+    ///
+    /// # Errors
+    ///
+    /// If the memory cannot be acquired, an error is returned, and nothing is acquired.
+    fn alloc_fresh(&mut self, size: usize, align: usize) -> Result<Block, AllocError>;
+
+    /// Extend the heap right after some empty block.
+    ///
+    /// This acquires exactly `size` fresh bytes starting at `end`, if the breaker allows so (e.g.
+    /// `end` is the program break). It is used for growing the top block inplace.
+    ///
+    /// # Errors
+    ///
+    /// If the memory cannot be acquired at `end`, an error is returned, and nothing is acquired.
+    fn extend_fresh(&mut self, _end: &Block, _size: usize) -> Result<Block, AllocError> {
+        Err(AllocError::OutOfMemory)
+    }
 
     /// Called right before new memory is added to the pool.
     fn on_new_memory(&mut self) {}
 
+    /// Called right after a block is allocated.
+    ///
+    /// `reused` tells if the block was served from the pool, rather than from fresh memory.
+    fn on_alloc(&mut self, _block: &Block, _reused: bool) {}
+
+    /// Is this the global allocator?
+    ///
+    /// This is reported to the OOM handlers.
+    fn is_global(&self) -> bool;
+
+    /// Handle a failed reservation of the block pool.
+    ///
+    /// The pool cannot be left in an intermediate state, hence the OOM handlers are called
+    /// without retrying.
+    #[cold]
+    fn reserve_oom(&self, err: AllocError) -> ! {
+        fail::oom_fatal(&OomInfo::new((self.pool.len() + 1) * mem::size_of::<Block>(),
+                                      mem::align_of::<Block>(), err, self.is_global()))
+    }
+
     /// Allocate a chunk of memory.
     ///
     /// This function takes a size and an alignment. From these a fitting block is found, to which
@@ -328,29 +462,50 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
     /// ```
     ///
     /// A block representing the marked area is then returned.
-    fn alloc(&mut self, size: usize, align: usize) -> Block {
+    ///
+    /// # Errors
+    ///
+    /// If no fitting block exists, and fresh memory cannot be acquired, an error is returned, and
+    /// the pool is left intact.
+    fn try_alloc(&mut self, size: usize, align: usize) -> Result<Block, AllocError> {
         // Logging.
         bk_log!(self, "Allocating {} bytes with alignment {}.", size, align);
 
-        if let Some((n, b)) = self.pool.iter_mut().enumerate().filter_map(|(n, i)| {
-            if i.size() >= size {
-                // Try to split at the aligner.
-                i.align(align).and_then(|(mut a, mut b)| {
-                    if b.size() >= size {
-                        // Override the old block.
-                        *i = a;
-                        Some((n, b))
-                    } else {
-                        // Put the split block back together and place it back in its spot.
-                        a.merge_right(&mut b).expect("Unable to merge block right.");
-                        *i = a;
-                        None
-                    }
-                })
-            } else {
-                None
-            }
-        }).next() {
+        // Compact the pool, if a memtrim asked for it.
+        if self.compact_pending {
+            self.compact();
+        }
+
+        // Make sure that freeing the excessive parts of the block won't need to reserve, such
+        // that the allocation cannot fail midway.
+        if let Some(x) = unborrow!(self.reserve(self.pool.len() + 2))? {
+            self.free(x);
+        }
+
+        // In `security` mode, we choose a random block among the first few fitting blocks to make
+        // the heap layout unpredictable. Otherwise, we simply use the first fitting block.
+        let skip = if cfg!(feature = "security") {
+            let candidates = self.pool.iter()
+                .filter(|x| x.fits(size, align))
+                .take(RANDOM_CANDIDATES)
+                .count();
+
+            if candidates == 0 { 0 } else { random::below(candidates) }
+        } else {
+            0
+        };
+
+        let ind = self.pool.iter()
+            .enumerate()
+            .filter(|&(_, x)| x.fits(size, align))
+            .map(|(n, _)| n)
+            .nth(skip);
+
+        if let Some(n) = ind {
+            // Split at the aligner, and leave the aligner in the old block's spot.
+            let (aligner, b) = self.pool[n].align(align).expect("Unable to align fitting block.");
+            self.pool[n] = aligner;
+
             // Update the pool byte count.
             self.total_bytes -= b.size();
 
@@ -359,12 +514,24 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
                 let _ = self.remove_at(n);
             }
 
-            // Split and mark the block uninitialized to the debugger.
-            let (res, excessive) = b.mark_uninitialized().split(size);
+            // In `security` mode, we place the allocation at a random (aligned) offset into the
+            // block, if it is oversized.
+            let (preceding, b) = if cfg!(feature = "security") && b.size() - size >= align {
+                let steps = cmp::min(b.size() - size, RANDOM_MAX_OFFSET) / align;
+                b.split(random::below(steps + 1) * align)
+            } else {
+                b.split(0)
+            };
+
+            // Split the block.
+            let (res, excessive) = b.split(size);
+            // Check the block and hand it out. The slack stays marked free.
+            let res = reuse(res);
 
             // There are many corner cases that make knowing where to insert it difficult
             // so we search instead.
             self.free(excessive);
+            self.free(preceding);
 
             // Check consistency.
             self.check();
@@ -372,13 +539,37 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
             debug_assert!(res.size() == size, "Requested space does not match with the returned \
                           block.");
 
-            res
+            // Trigger the allocation event handler.
+            self.on_alloc(&res, true);
+
+            Ok(res)
         } else {
             // No fitting block found. Allocate a new block.
-            self.alloc_external(size, align)
+            let mut res = self.alloc_external(size, align)?;
+
+            // Fill it with the uninitialized pattern.
+            res.poison_uninitialized();
+
+            // Trigger the allocation event handler.
+            self.on_alloc(&res, false);
+
+            Ok(res)
         }
     }
 
+    /// Allocate a chunk of memory.
+    ///
+    /// See [`try_alloc`](#method.try_alloc) for more information.
+    ///
+    /// # Errors
+    ///
+    /// The OOM handler handles out-of-memory conditions.
+    #[inline]
+    fn alloc(&mut self, size: usize, align: usize) -> Block {
+        self.try_alloc(size, align)
+            .unwrap_or_else(|err| fail::oom_fatal(&OomInfo::new(size, align, err, self.is_global())))
+    }
+
     /// Free a memory block.
     ///
     /// After this have been called, no guarantees are made about the passed pointer. If it want
@@ -433,6 +624,29 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
         self.free_bound(bound, block);
     }
 
+    /// Free a block, which is already poisoned and marked free.
+    ///
+    /// This is used for moving blocks between pools. In contrast to [`free`](#method.free), the
+    /// block is neither checked nor poisoned again, as it is written to and reported to the
+    /// debugger exactly once, when it is freed.
+    fn free_prepared(&mut self, block: Block) {
+        // Logging.
+        bk_log!(self, "Freeing {:?} from another pool...", block);
+
+        let bound = self.find_bound(&block);
+        self.free_prepared_bound(bound, block);
+    }
+
+    /// Free a block on behalf of the user.
+    ///
+    /// In contrast to [`free`](#method.free), this is only used for buffers handed back by the
+    /// user, which allows the allocator to delay their reuse. By default, this simply frees the
+    /// block.
+    #[inline]
+    fn free_user(&mut self, block: Block) {
+        self.free(block);
+    }
+
     /// Reallocate memory.
     ///
     /// If necessary (inplace reallocation is not possible or feasible) it will allocate a new
@@ -464,7 +678,12 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
     /// space as free. If these conditions are not met, we have to allocate a new list, and then
     /// deallocate the old one, after which we use memmove to copy the data over to the newly
     /// allocated list.
-    fn realloc(&mut self, block: Block, new_size: usize, align: usize) -> Block {
+    ///
+    /// # Errors
+    ///
+    /// If a new buffer is needed, but cannot be allocated, an error is returned. The old buffer
+    /// is then left intact.
+    fn try_realloc(&mut self, block: Block, new_size: usize, align: usize) -> Result<Block, AllocError> {
         // Find the index bound.
         let ind = self.find_bound(&block);
 
@@ -473,19 +692,20 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
 
         // Try to do an inplace reallocation.
         match self.realloc_inplace_bound(ind, block, new_size) {
-            Ok(block) => block,
+            Ok(block) => Ok(block),
             Err(block) => {
                 // Reallocation cannot be done inplace.
 
-                // Allocate a new block with the same size.
-                let mut res = self.alloc(new_size, align);
+                // Allocate a new block with the same size. On failure, the old block is simply
+                // handed back to the caller.
+                let mut res = self.try_alloc(new_size, align)?;
 
                 // Copy the old data to the new location.
                 block.copy_to(&mut res);
 
-                // Free the old block.
+                // Free the old block on behalf of the user.
                 // Allocation may have moved insertion so we search again.
-                self.free(block);
+                self.free_user(block);
 
                 // Check consistency.
                 self.check();
@@ -493,11 +713,24 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
                 debug_assert!(res.size() >= new_size, "Requested space does not match with the \
                               returned block.");
 
-                res
+                Ok(res)
             },
         }
     }
 
+    /// Reallocate memory.
+    ///
+    /// See [`try_realloc`](#method.try_realloc) for more information.
+    ///
+    /// # Errors
+    ///
+    /// The OOM handler handles out-of-memory conditions.
+    #[inline]
+    fn realloc(&mut self, block: Block, new_size: usize, align: usize) -> Block {
+        self.try_realloc(block, new_size, align)
+            .unwrap_or_else(|err| fail::oom_fatal(&OomInfo::new(new_size, align, err, self.is_global())))
+    }
+
     /// Extend/shrink the buffer inplace.
     ///
     /// This will try to extend the buffer without copying, if the new size is larger than the old
@@ -567,18 +800,21 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
                 // Logging...
                 bk_log!(self;ind, "Merging {:?} to the right.", block);
 
-                // We'll merge it with the block at the end of the range.
-                block.merge_right(&mut self.remove_at(ind.end))
+                // We'll take the missing bytes from the block at the end of the range.
+                let (taken, excessive) = self.remove_at(ind.end).split(new_size - block.size());
+                // Check the taken bytes like any reused block.
+                block.merge_right(&mut reuse(taken))
                     .expect("Unable to merge block right, to the end of the range.");
                 // Merge succeeded.
 
-                // Place the excessive block back.
-                let (res, excessive) = block.split(new_size);
-                // Remove_at may have shortened the vector.
+                // Place the excessive block back. Remove_at may have shortened the vector.
                 if ind.start == self.pool.len() {
-                    self.push(excessive);
+                    self.push_prepared(excessive.mark_free());
                 } else if !excessive.is_empty() {
-                    self.pool[ind.start] = excessive;
+                    // Update the pool byte count.
+                    self.total_bytes += excessive.size();
+
+                    self.pool[ind.start] = excessive.mark_free();
                 }
                 // Block will still not be adjacent, due to `excessive` being guaranteed to not be
                 // adjacent to the next block.
@@ -586,7 +822,39 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
                 // Run a consistency check.
                 self.check();
 
-                return Ok(res);
+                return Ok(block);
+            }
+
+            // The block (possibly merged with its right neighbor) might end at the top of the heap,
+            // in which case the missing bytes can be acquired right after it.
+            let (right, needed, end) = match self.pool.get(ind.end) {
+                Some(entry) if block.left_to(entry) => {
+                    (true, new_size - block.size() - entry.size(), entry.empty_right())
+                },
+                _ => (false, new_size - block.size(), block.empty_right()),
+            };
+
+            if let Ok(mut extension) = self.extend_fresh(&end, needed) {
+                // Logging...
+                bk_log!(self;ind, "Extending {:?} by {} fresh bytes.", block, needed);
+
+                if right {
+                    // Check the right neighbor as any other reused block.
+                    block.merge_right(&mut reuse(self.remove_at(ind.end)))
+                        .expect("Unable to merge block right, to the end of the range.");
+                }
+                // Fill the fresh segment with the uninitialized pattern.
+                extension.poison_uninitialized();
+                block.merge_right(&mut extension)
+                    .expect("The fresh segment is not adjacent to the block.");
+
+                // Make some assertions to avoid dumb bugs.
+                debug_assert!(block.size() == new_size, "Block wasn't extended properly.");
+
+                // Run a consistency check.
+                self.check();
+
+                return Ok(block);
             }
         }
 
@@ -606,11 +874,41 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
         // Short circuit in case of empty block.
         if block.is_empty() { return; }
 
+        // Inserting a block overlapping with a free block will corrupt the pool, so in debug and
+        // hardened builds, we check the neighbors of the bound (this catches double frees).
+        if cfg!(debug_assertions) || cfg!(feature = "hardened") {
+            if let Some(free) = self.find_overlap(&ind, &block) {
+                fail::corruption(Corruption::OverlappingFree {
+                    ptr: *Pointer::from(block.empty_left()),
+                    size: block.size(),
+                    free_ptr: *Pointer::from(free.empty_left()),
+                    free_size: free.size(),
+                });
+
+                // The handler returned, so we leak the block instead of corrupting the pool.
+                return;
+            }
+        }
+
         // When compiled with `security`, we zero this block.
         block.sec_zero();
+        // When compiled with `poison`, we fill it with the free pattern.
+        block.poison();
+        // Mark it free to the debugger, as merging it bypasses `insert`.
+        let block = block.mark_free();
+
+        self.free_prepared_bound(ind, block);
+    }
+
+    /// Free a block, which is already poisoned and marked free, in some index bound.
+    ///
+    /// See [`free_prepared`](#method.free_prepared) for more information.
+    fn free_prepared_bound(&mut self, ind: Range<usize>, mut block: Block) {
+        // Short circuit in case of empty block.
+        if block.is_empty() { return; }
 
         if ind.start == self.pool.len() {
-            self.push(block);
+            self.push_prepared(block);
             return;
         }
 
@@ -621,7 +919,7 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
         // Try to merge it with the block to the right.
         if ind.end < self.pool.len() && block.left_to(&self.pool[ind.end]) {
             // Merge the block with the rightmost block in the range.
-            block.merge_right(&mut self.remove_at(ind.end))
+            block.merge_right(&mut self.remove_at(ind.end).mark_free())
                 .expect("Unable to merge block right to the block at the end of the range");
 
             // The merging succeeded. We proceed to try to close in the possible gap.
@@ -651,7 +949,7 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
     /// "Fresh" means that the space is allocated through the breaker.
     ///
     /// The returned pointer is guaranteed to be aligned to `align`.
-    fn alloc_external(&mut self, size: usize, align: usize) -> Block {
+    fn alloc_external(&mut self, size: usize, align: usize) -> Result<Block, AllocError> {
         // Logging.
         bk_log!(self, "Fresh allocation of size {} with alignment {}.", size, align);
 
@@ -665,14 +963,25 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
     }
 
     /// Push an element without reserving.
+    ///
+    /// This is used for fresh memory, which is poisoned and marked free first.
     // TODO: Make `push` and `free` one.
-    fn push(&mut self, block: Block) {
+    fn push(&mut self, mut block: Block) {
+        // Poison the block and mark it free.
+        block.poison();
+        let block = block.mark_free();
+
+        self.push_prepared(block);
+    }
+
+    /// Push an element, which is already poisoned and marked free, without reserving.
+    ///
+    /// In contrast to [`push`](#method.push), the block is not poisoned again, as it comes from
+    /// the pool (or has been prepared by `free_bound`).
+    fn push_prepared(&mut self, mut block: Block) {
         // Logging.
         bk_log!(self;self.pool.len(), "Pushing {:?}.", block);
 
-        // Mark the block free.
-        let mut block = block.mark_free();
-
         // Short-circuit in case on empty block.
         if !block.is_empty() {
             // Trigger the new memory event handler.
@@ -693,7 +1002,9 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
             }
 
             // Reserve space and free the old buffer.
-            if let Some(x) = unborrow!(self.reserve(self.pool.len() + 1)) {
+            let old_buf = unborrow!(self.reserve(self.pool.len() + 1))
+                .unwrap_or_else(|err| self.reserve_oom(err));
+            if let Some(x) = old_buf {
                 // Note that we do not set the count down because this isn't setting back our
                 // pushed block.
 
@@ -725,7 +1036,7 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
                 self.total_bytes -= block.size();
 
                 // Can't push because reserve changed the end of the pool.
-                self.free(block);
+                self.free_prepared(block);
             }
         }
 
@@ -739,14 +1050,18 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
     ///
     /// This is assumed to not modify the order. If some block `b` is associated with index `i`
     /// prior to call of this function, it should be too after it.
-    fn reserve(&mut self, min_cap: usize) -> Option<Block> {
+    ///
+    /// # Errors
+    ///
+    /// If the new buffer cannot be allocated, an error is returned, and the pool is left intact.
+    fn reserve(&mut self, min_cap: usize) -> Result<Option<Block>, AllocError> {
         // Logging.
         bk_log!(self;min_cap, "Reserving {}.", min_cap);
 
         if !self.reserving && (self.pool.capacity() < self.pool.len() + EXTRA_ELEMENTS || self.pool.capacity() < min_cap + EXTRA_ELEMENTS) {
             // Reserve a little extra for performance reasons.
             // TODO: This should be moved to some new method.
-            let new_cap = min_cap + EXTRA_ELEMENTS + config::extra_fresh(min_cap);
+            let new_cap = min_cap + EXTRA_ELEMENTS + extra_fresh(min_cap);
 
             // Catch 'em all.
             debug_assert!(new_cap > self.pool.capacity(), "Reserve shrinks?!");
@@ -763,9 +1078,65 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
             // Check consistency.
             self.check();
 
-            Some(self.pool.refill(new_buf))
+            Ok(Some(self.pool.refill(new_buf?)))
         } else {
-            None
+            Ok(None)
+        }
+    }
+
+    /// Compact the block pool.
+    ///
+    /// This removes the empty blocks from the pool. If the capacity of the pool is then more than
+    /// `POOL_SHRINK_FACTOR` times what it needs, the pool is moved to a smaller segment, and the
+    /// old segment is freed.
+    ///
+    /// The size of the freed segment is returned (zero, if the pool wasn't moved).
+    fn compact(&mut self) -> usize {
+        // Logging.
+        bk_log!(self, "Compacting the pool.");
+
+        // Don't compact while the pool is moving. The pending compaction is kept, such that it is
+        // done on a later allocation.
+        if self.reserving { return 0; }
+
+        self.compact_pending = false;
+
+        // Move the non-empty blocks down, preserving the order.
+        let mut len = 0;
+        for i in 0..self.pool.len() {
+            if !self.pool[i].is_empty() {
+                self.pool.swap(len, i);
+                len += 1;
+            }
+        }
+        // Drop the empty blocks, which are now trailing.
+        self.pool.truncate(len);
+
+        // Check consistency.
+        self.check();
+
+        // The allocation below adds at most two blocks to the pool.
+        let new_cap = len + 2 + EXTRA_ELEMENTS + extra_fresh(len);
+        if new_cap.saturating_mul(POOL_SHRINK_FACTOR) > self.pool.capacity() {
+            return 0;
+        }
+
+        // Make sure the pool isn't reserved, while allocating its new segment.
+        self.reserving = true;
+        let new_buf = self.try_alloc(new_cap * mem::size_of::<Block>(), mem::align_of::<Block>());
+        self.reserving = false;
+
+        match new_buf {
+            Ok(new_buf) => {
+                // Move the pool, and free the old segment.
+                let old_buf = self.pool.refill(new_buf);
+                let size = old_buf.size();
+                self.free(old_buf);
+
+                size
+            },
+            // Keep the old segment.
+            Err(_) => 0,
         }
     }
 
@@ -883,7 +1254,8 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
 
                           // Reserve space. This does not break order, due to the assumption that
                           // `reserve` never breaks order.
-                          old_buf = unborrow!(self.reserve(self.pool.len() + 1));
+                          old_buf = unborrow!(self.reserve(self.pool.len() + 1))
+                              .unwrap_or_else(|err| self.reserve_oom(err));
 
                           // We will move a block into reserved memory but outside of the vec's bounds. For
                           // that reason, we push an uninitialized element to extend the length, which will
@@ -954,3 +1326,168 @@ pub trait Allocator: ops::DerefMut<Target = Bookkeeper> {
         res.mark_uninitialized()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use sim::{Event, SimAllocator, Source};
+
+    #[test]
+    fn test_verify() {
+        let mut buf = [0u64; 4096];
+        let mut alloc = SimAllocator::new(unsafe {
+            Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+        });
+
+        // Every fresh allocation leaves a free block after it.
+        let a = alloc.alloc(64, 8);
+        let b = alloc.alloc(64, 8);
+        assert!(alloc.pool.len() >= 3);
+        assert_eq!(alloc.verify(), Ok(()));
+
+        // Wrong byte count.
+        alloc.total_bytes += 1;
+        match alloc.verify() {
+            Err(HeapError::ByteCount { .. }) => (),
+            x => panic!("Expected byte count error, got {:?}.", x),
+        }
+        alloc.total_bytes -= 1;
+
+        // Unsorted blocks.
+        alloc.pool.swap(0, 1);
+        assert_eq!(alloc.verify(), Err(HeapError::Unsorted { index: 0 }));
+        alloc.pool.swap(0, 1);
+
+        // Adjacent blocks.
+        let adjacent = unsafe {
+            let ptr = *Pointer::from(alloc.pool[1].empty_left());
+            Block::from_raw_parts(Pointer::new(ptr.offset(-8)), 8)
+        };
+        let old = mem::replace(&mut alloc.pool[0], adjacent);
+        assert_eq!(alloc.verify(), Err(HeapError::Adjacent { index: 0 }));
+        alloc.pool[0] = old;
+
+        // An empty block, which doesn't share the address of its right neighbor.
+        let empty = alloc.pool[0].empty_left();
+        let old = mem::replace(&mut alloc.pool[0], empty);
+        assert_eq!(alloc.verify(), Err(HeapError::MisplacedEmpty { index: 0 }));
+        alloc.pool[0] = old;
+
+        assert_eq!(alloc.verify(), Ok(()));
+
+        alloc.free(a);
+        alloc.free(b);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut buf = [0u64; 65536];
+        let mut alloc = SimAllocator::new(unsafe {
+            Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+        });
+
+        // Fragment the heap, such that the pool grows.
+        let mut even = ::std::vec::Vec::new();
+        let mut odd = ::std::vec::Vec::new();
+        for _ in 0..1000 {
+            even.push(alloc.alloc(16, 8));
+            odd.push(alloc.alloc(16, 8));
+        }
+        for block in even.drain(..) {
+            alloc.free(block);
+        }
+
+        // Fill the holes (merged with the excessive space of their neighbors) again, leaving the
+        // pool sparse.
+        for _ in 0..999 {
+            even.push(alloc.alloc(32, 8));
+        }
+
+        let cap = alloc.capacity();
+        assert!(alloc.compact() > 0);
+        assert!(alloc.capacity() < cap);
+        assert_eq!(alloc.verify(), Ok(()));
+
+        // Compacting again does nothing.
+        assert_eq!(alloc.compact(), 0);
+
+        // A compaction scheduled while the pool is moving is kept.
+        alloc.schedule_compaction();
+        alloc.reserving = true;
+        assert_eq!(alloc.compact(), 0);
+        assert!(alloc.compact_pending);
+        alloc.reserving = false;
+        alloc.compact();
+        assert!(!alloc.compact_pending);
+
+        for block in even.drain(..).chain(odd.drain(..)) {
+            alloc.free(block);
+        }
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut buf = [0u64; 4096];
+        let mut alloc = SimAllocator::new(unsafe {
+            Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+        });
+
+        // Acquire the top block, and drop the excessive space.
+        let block = alloc.alloc(1000, 8);
+        let excessive = alloc.pop().unwrap();
+        let used = alloc.source.used();
+        assert_eq!(alloc.source.brk(ptr::null()),
+                   *Pointer::from(excessive.empty_right()) as *const u8);
+        alloc.free(excessive);
+
+        // The top block grows inplace, taking the excessive space and the missing bytes from the
+        // break.
+        alloc.source.clear_events();
+        let block = alloc.realloc_inplace(block, 2000).unwrap();
+        assert_eq!(block.size(), 2000);
+        assert!(alloc.source.used() > used);
+        assert_eq!(alloc.source.events().len(), 1);
+        match alloc.source.events()[0] {
+            Event::Grow { offset, .. } => assert_eq!(offset, used),
+            x => panic!("Expected grow, got {:?}.", x),
+        }
+        assert_eq!(alloc.source.brk(ptr::null()),
+                   *Pointer::from(block.empty_right()) as *const u8);
+        assert_eq!(alloc.verify(), Ok(()));
+
+        // A failing source leaves the block intact.
+        alloc.source.fail_next(1);
+        let block = alloc.realloc_inplace(block, 3000).unwrap_err();
+        assert_eq!(block.size(), 2000);
+
+        alloc.free(block);
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(feature = "poison")]
+    fn test_realloc_use_after_free() {
+        fn panic(_: Corruption) {
+            panic!("Use-after-free detected.");
+        }
+
+        fail::set_corruption_handler(panic);
+
+        let mut buf = [0u64; 4096];
+        let mut alloc = SimAllocator::new(unsafe {
+            Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+        });
+
+        // Free the tail of the block, and write to it afterwards.
+        let (block, tail) = alloc.alloc(64, 8).split(32);
+        let ptr = *Pointer::from(tail.empty_left());
+        alloc.free(tail);
+        unsafe {
+            *ptr = 0;
+        }
+
+        // Growing the block into the tail detects the write.
+        let _ = alloc.realloc_inplace(block, 48);
+    }
+}
@@ -4,18 +4,31 @@
 
 use prelude::*;
 
-use core::ptr;
+use core::{cmp, ptr, mem};
 use core::convert::TryInto;
 
-use shim::{syscalls, config};
+use shim::syscalls;
+use shim::config::PAGE_SIZE;
 
-use {sync, fail};
+use {config, sync, stats, limit};
+use config::Param;
+use fail::AllocError;
+#[cfg(feature = "validate")]
+use owned;
+#[cfg(feature = "simulation")]
+use sim;
+#[cfg(feature = "fault_injection")]
+use faults;
 
 /// The BRK mutex.
 ///
 /// This is used for avoiding data races in multiple allocator.
 static BRK_MUTEX: Mutex<BrkState> = Mutex::new(BrkState {
     current_brk: None,
+    growth: Growth {
+        chunk: 0,
+        last: 0,
+    },
 });
 
 /// A cache of the BRK state.
@@ -24,6 +37,56 @@ static BRK_MUTEX: Mutex<BrkState> = Mutex::new(BrkState {
 struct BrkState {
     /// The program break's end
     current_brk: Option<Pointer<u8>>,
+    /// The adaptive growth of the program break.
+    growth: Growth,
+}
+
+/// Canonicalize a BRK request.
+///
+/// This follows the curve of the shim, with the parameters of the runtime configuration.
+#[inline]
+fn extra_brk(size: usize) -> usize {
+    cmp::max(config::get(Param::BrkMinExtra),
+             cmp::min(size.saturating_mul(config::get(Param::BrkMultiplier)),
+                      config::get(Param::BrkMaxExtra)))
+}
+
+/// The adaptive growth of the program break.
+///
+/// Allocation-heavy phases would make a BRK for every few allocations, if the extra space followed
+/// the curve of `extra_brk` alone. Instead, the extra space is doubled on every BRK made within
+/// `Param::BrkGrowthWindow` allocations of the previous one (up to `Param::BrkAdaptiveMax`), and
+/// halved for every window passed without BRKs.
+struct Growth {
+    /// The current extra size.
+    ///
+    /// The extra size never goes below the curve of `extra_brk`.
+    chunk: usize,
+    /// The number of allocations at the previous BRK.
+    last: usize,
+}
+
+impl Growth {
+    /// Get the extra size of a BRK of `size` bytes, made at `now` allocations.
+    fn extra(&mut self, size: usize, now: usize) -> usize {
+        let base = extra_brk(size);
+        let window = cmp::max(config::get(Param::BrkGrowthWindow), 1);
+        let halvings = now.wrapping_sub(self.last) / window;
+
+        self.chunk = if halvings == 0 {
+            // We're growing rapidly, so we double the extra space.
+            cmp::min(cmp::max(self.chunk.saturating_mul(2), base),
+                     config::get(Param::BrkAdaptiveMax))
+        } else if halvings < 64 {
+            // The growth stopped, so we decay.
+            self.chunk >> halvings
+        } else {
+            0
+        };
+        self.last = now;
+
+        cmp::max(self.chunk, base)
+    }
 }
 
 /// A BRK lock.
@@ -46,7 +109,8 @@ impl BrkLock {
         let expected_brk = self.current_brk().offset(size);
 
         // Break it to me, babe!
-        let old_brk = Pointer::new(syscalls::brk(*expected_brk as *const u8) as *mut u8);
+        let old_brk = Pointer::new(brk(*expected_brk as *const u8) as *mut u8);
+        stats::brk_called();
 
         /// AAAARGH WAY TOO MUCH LOGGING
         ///
@@ -90,6 +154,16 @@ impl BrkLock {
             // In debug mode, we want to check for WTF-worthy scenarios.
             debug_assert!(res.is_ok(), "Failed to set the program break back.");
 
+            // Update the heap statistics.
+            stats::heap_shrunk(block.size());
+
+            // The segment is no longer ours.
+            #[cfg(feature = "validate")]
+            {
+                let size = block.size();
+                owned::release(*Pointer::from(block) as *const u8, size);
+            }
+
             Ok(())
         } else {
             // Logging...
@@ -103,7 +177,7 @@ impl BrkLock {
     /// Get the current program break.
     ///
     /// If not available in the cache, requested it from the OS.
-    fn current_brk(&mut self) -> Pointer<u8> {
+    pub fn current_brk(&mut self) -> Pointer<u8> {
         if let Some(ref cur) = self.state.current_brk {
             let res = cur.clone();
             // Make sure that the break is set properly (i.e. there is no libc interference).
@@ -122,32 +196,99 @@ impl BrkLock {
         cur
     }
 
+    /// Extend the segment ending at the program break.
+    ///
+    /// Exactly `size` bytes are acquired right after `end`, which must be (an empty block at) the
+    /// current program break. This allows growing the top block inplace.
+    ///
+    /// # Errors
+    ///
+    /// If `end` is not the program break, or the needed space cannot be acquired, an error is
+    /// returned, and the program break is left untouched.
+    pub fn extend(&mut self, end: &Block, size: usize) -> Result<Block, AllocError> {
+        if self.current_brk() != Pointer::from(end.empty_left()) {
+            return Err(AllocError::OutOfMemory);
+        }
+
+        // Respect the heap limit.
+        limit::check_heap(size)?;
+
+        // The conversion is failable, as the size might not fit the address space.
+        let brk_delta = size.try_into().map_err(|_| AllocError::SizeOverflow)?;
+
+        // Fail on demand.
+        #[cfg(feature = "fault_injection")]
+        faults::inject(size)?;
+
+        let segment = unsafe {
+            // The program break is only grown, hence no memory in use is released.
+            self.sbrk(brk_delta).map_err(|()| AllocError::OutOfMemory)?
+        };
+
+        // Update the heap statistics.
+        stats::heap_grown(size);
+
+        // Keep track of the acquired segment (it is merged with the one it extends).
+        #[cfg(feature = "validate")]
+        owned::acquire(*segment as *const u8, size);
+
+        Ok(unsafe {
+            // The segment was just acquired.
+            Block::from_raw_parts(segment, size)
+        })
+    }
+
     /// BRK new space.
     ///
     /// The first block represents the aligner segment (that is the precursor aligning the middle
     /// block to `align`), the second one is the result and is of exactly size `size`. The last
     /// block is the excessive space.
     ///
-    /// # Failure
+    /// # Errors
     ///
-    /// This method calls the OOM handler if it is unable to acquire the needed space.
+    /// If the needed space cannot be acquired (or the size overflows), an error is returned, and
+    /// the program break is left untouched.
     // TODO: This method is possibly unsafe.
-    pub fn canonical_brk(&mut self, size: usize, align: usize) -> (Block, Block, Block) {
-        // Calculate the canonical size (extra space is allocated to limit the number of system calls).
-        let brk_size = size + config::extra_brk(size) + align;
+    pub fn canonical_brk(&mut self, size: usize, align: usize) -> Result<(Block, Block, Block), AllocError> {
+        // Calculate the canonical size (extra space is allocated to limit the number of system
+        // calls, and grows along with the program). Important! The arithmetic is checked to avoid
+        // overflow-based attacks.
+        let min_size = size.checked_add(align).ok_or(AllocError::SizeOverflow)?;
+        let extra = self.state.growth.extra(size, stats::stats().allocations);
+        let mut brk_size = min_size.checked_add(extra).ok_or(AllocError::SizeOverflow)?;
+
+        // Respect the heap limit. If the extra space is what breaks the limit, we leave it out.
+        if limit::check_heap(brk_size).is_err() {
+            limit::check_heap(min_size)?;
+            brk_size = min_size;
+        }
+
+        // The conversion is failable for the same reason.
+        let brk_delta = brk_size.try_into().map_err(|_| AllocError::SizeOverflow)?;
+
+        // Fail on demand.
+        #[cfg(feature = "fault_injection")]
+        faults::inject(brk_size)?;
+
+        // Use SBRK to allocate extra data segment.
+        let segment = unsafe {
+            // The program break is only grown, hence no memory in use is released.
+            self.sbrk(brk_delta).map_err(|()| AllocError::OutOfMemory)?
+        };
+
+        // Update the heap statistics.
+        stats::heap_grown(brk_size);
 
-        // Use SBRK to allocate extra data segment. The alignment is used as precursor for our
-        // allocated block. This ensures that it is properly memory aligned to the requested value.
-        // TODO: Audit the casts.
+        // Keep track of the acquired segment.
+        #[cfg(feature = "validate")]
+        owned::acquire(*segment as *const u8, brk_size);
+
+        // The alignment is used as precursor for our allocated block. This ensures that it is
+        // properly memory aligned to the requested value.
         let (alignment_block, rest) = unsafe {
             // LAST AUDIT: 2016-08-21 (Ticki).
 
-            Block::from_raw_parts(
-                // Important! The conversion is failable to avoid arithmetic overflow-based
-                // attacks.
-                self.sbrk(brk_size.try_into().unwrap()).unwrap_or_else(|()| fail::oom()),
-                brk_size,
-            )
+            Block::from_raw_parts(segment, brk_size)
         }.align(align).unwrap();
 
         // Split the block to leave the excessive space.
@@ -157,7 +298,7 @@ impl BrkLock {
         debug_assert!(res.aligned_to(align), "Alignment failed.");
         debug_assert!(res.size() + alignment_block.size() + excessive.size() == brk_size, "BRK memory leak.");
 
-        (alignment_block, res, excessive)
+        Ok((alignment_block, res, excessive))
     }
 }
 
@@ -168,6 +309,65 @@ pub fn lock() -> BrkLock {
     }
 }
 
+/// Acquire the BRK lock before forking.
+///
+/// The lock is kept until `fork_unlock` is called.
+pub fn fork_lock() {
+    mem::forget(BRK_MUTEX.lock());
+}
+
+/// Release the BRK lock after forking.
+///
+/// In the child, the cached program break is reset, such that it is requested from the OS again.
+///
+/// # Safety
+///
+/// This must be paired with a preceding call to `fork_lock`.
+pub unsafe fn fork_unlock(child: bool) {
+    BRK_MUTEX.force_unlock();
+
+    if child {
+        BRK_MUTEX.lock().current_brk = None;
+    }
+}
+
+/// Purge the interior pages of a free block.
+///
+/// This tells the OS that the pages entirely contained in the block are unused, such that
+/// their physical memory can be reclaimed. The content of the pages is lost. The number of
+/// bytes purged is returned.
+///
+/// When compiled with `poison`, this does nothing, as the pattern of free blocks must be kept
+/// intact.
+pub fn purge(block: &Block) -> usize {
+    if cfg!(feature = "poison") {
+        return 0;
+    }
+
+    // Find the pages entirely contained in the block.
+    let start = *Pointer::from(block.empty_left()) as usize;
+    let end = start + block.size();
+    let page_start = start.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let page_end = end & !(PAGE_SIZE - 1);
+
+    if page_end <= page_start {
+        return 0;
+    }
+
+    let res = unsafe {
+        // The pages are contained in a free block, hence nobody uses their content.
+        syscalls::madvise_dontneed(page_start as *mut u8, page_end - page_start)
+    };
+
+    if res == 0 {
+        log!(DEBUG, "Purged {} bytes of {:?}.", page_end - page_start, block);
+
+        page_end - page_start
+    } else {
+        0
+    }
+}
+
 /// `SBRK` symbol which can coexist with the allocator.
 ///
 /// `SBRK`-ing directly (from the `BRK` syscall or libc) might make the state inconsistent. This
@@ -184,11 +384,28 @@ pub unsafe extern fn sbrk(size: isize) -> *mut u8 {
 }
 
 /// Get the current program break.
-fn current_brk() -> Pointer<u8> {
+pub fn current_brk() -> Pointer<u8> {
     unsafe {
         // LAST AUDIT: 2016-08-21 (Ticki).
 
-        Pointer::new(syscalls::brk(ptr::null()) as *mut u8)
+        Pointer::new(brk(ptr::null()) as *mut u8)
+    }
+}
+
+/// Set the program break.
+///
+/// This is the BRK system call of the shim. When compiled with `simulation`, the program break is
+/// simulated instead (see the `sim` module).
+#[inline]
+unsafe fn brk(ptr: *const u8) -> *const u8 {
+    #[cfg(feature = "simulation")]
+    {
+        sim::brk(ptr)
+    }
+
+    #[cfg(not(feature = "simulation"))]
+    {
+        syscalls::brk(ptr)
     }
 }
 
@@ -197,20 +414,31 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_ordered() {
-        let brk = lock().canonical_brk(20, 1);
-
-        assert!(brk.0 <= brk.1);
-        assert!(brk.1 <= brk.2);
+    fn test_overflow() {
+        assert_eq!(lock().canonical_brk(!0, 1).err(), Some(AllocError::SizeOverflow));
+        assert_eq!(lock().canonical_brk(!0 >> 1, 1).err(), Some(AllocError::SizeOverflow));
     }
 
     #[test]
-    fn test_brk_grow_up() {
-        unsafe {
-            let brk1 = lock().sbrk(5).unwrap();
-            let brk2 = lock().sbrk(100).unwrap();
-
-            assert!(*brk1 < *brk2);
+    fn test_growth() {
+        let mut growth = Growth {
+            chunk: 0,
+            last: 0,
+        };
+        let base = extra_brk(64);
+        let window = config::get(Param::BrkGrowthWindow);
+
+        // Rapid growth.
+        assert_eq!(growth.extra(64, 1), base);
+        assert_eq!(growth.extra(64, 2), 2 * base);
+        assert_eq!(growth.extra(64, 3), 4 * base);
+        for n in 4..64 {
+            assert!(growth.extra(64, n) <= config::get(Param::BrkAdaptiveMax));
         }
+
+        // Decay.
+        let chunk = growth.chunk;
+        assert_eq!(growth.extra(64, 63 + 2 * window), cmp::max(chunk / 4, base));
+        assert_eq!(growth.extra(64, 63 + 100 * window), base);
     }
 }
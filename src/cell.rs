@@ -35,6 +35,18 @@ impl<T> MoveCell<T> {
     }
 }
 
+impl<T: Copy> MoveCell<T> {
+    /// Get a copy of the inner data.
+    #[inline]
+    pub fn get(&self) -> T {
+        unsafe {
+            // This is safe due to the data being `Copy`, and the inner value never being borrowed
+            // outside `replace`.
+            *self.inner.get()
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -44,5 +56,7 @@ mod test {
         let cell = MoveCell::new(200);
         assert_eq!(cell.replace(300), 200);
         assert_eq!(cell.replace(4), 300);
+        assert_eq!(cell.get(), 4);
+        assert_eq!(cell.get(), 4);
     }
 }
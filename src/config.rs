@@ -0,0 +1,165 @@
+//! Runtime configuration.
+//!
+//! This extends the compile-time configuration of the shim with tunables, which can be changed at
+//! runtime. They are initialized from the shim, and can be overridden through the `RALLOC_CONF`
+//! environment variable, e.g. `RALLOC_CONF="memtrim_limit=64m,log_level=3"`. The variable is
+//! parsed when the allocator is initialized.
+//!
+//! Furthermore, the tunables can be changed on the fly through [`set`](./fn.set.html), e.g. to
+//! trim aggressively during idle periods:
+//!
+//! ```rust
+//! use ralloc::config::{self, Param};
+//!
+//! let old = config::get(Param::LocalMemtrimLimit);
+//! config::set(Param::LocalMemtrimLimit, 0);
+//! // Idle...
+//! config::set(Param::LocalMemtrimLimit, old);
+//! ```
+//!
+//! The compile-time defaults of the tunables are re-exported as well.
+
+pub use shim::config::{OS_MEMTRIM_LIMIT, OS_MEMTRIM_WORTHY, FRAGMENTATION_SCALE,
+                       LOCAL_MEMTRIM_LIMIT, LOCAL_MEMTRIM_STOP, LOCAL_MEMTRIM_WINDOW,
+                       LOCAL_MEMTRIM_MAX, BRK_MULTIPLIER, BRK_MIN_EXTRA, BRK_MAX_EXTRA,
+                       BRK_ADAPTIVE_MAX, BRK_GROWTH_WINDOW, FRESH_MULTIPLIER, FRESH_MIN_EXTRA,
+                       FRESH_MAX_EXTRA, MIN_LOG_LEVEL, VERIFY_INTERVAL};
+
+use core::sync::atomic::{self, AtomicUsize};
+
+/// A runtime tunable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Param {
+    /// The memtrim limit (see `OS_MEMTRIM_LIMIT`).
+    MemtrimLimit,
+    /// The minimum size before a block is worthy to memtrim (see `OS_MEMTRIM_WORTHY`).
+    MemtrimWorthy,
+    /// The fragmentation scale (see `FRAGMENTATION_SCALE`).
+    FragmentationScale,
+    /// The local memtrim limit (see `LOCAL_MEMTRIM_LIMIT`).
+    LocalMemtrimLimit,
+    /// The local memtrim chock (see `LOCAL_MEMTRIM_STOP`).
+    LocalMemtrimStop,
+    /// The window of the local memtrim policy (see `LOCAL_MEMTRIM_WINDOW`).
+    LocalMemtrimWindow,
+    /// The maximal local memtrim limit (see `LOCAL_MEMTRIM_MAX`).
+    LocalMemtrimMax,
+    /// The BRK multiplier (see `BRK_MULTIPLIER`).
+    BrkMultiplier,
+    /// The minimum extra size to be BRK'd (see `BRK_MIN_EXTRA`).
+    BrkMinExtra,
+    /// The maximal amount of extra bytes to be BRK'd (see `BRK_MAX_EXTRA`).
+    BrkMaxExtra,
+    /// The maximal amount of extra bytes to be BRK'd, when growing rapidly (see
+    /// `BRK_ADAPTIVE_MAX`).
+    BrkAdaptiveMax,
+    /// The window of rapid growth of the program break (see `BRK_GROWTH_WINDOW`).
+    BrkGrowthWindow,
+    /// The multiplier of fresh allocations (see `FRESH_MULTIPLIER`).
+    FreshMultiplier,
+    /// The minimum extra size of fresh allocations (see `FRESH_MIN_EXTRA`).
+    FreshMinExtra,
+    /// The maximal amount of extra bytes of fresh allocations (see `FRESH_MAX_EXTRA`).
+    FreshMaxExtra,
+    /// The minimum log level (see `MIN_LOG_LEVEL`).
+    LogLevel,
+    /// The number of operations between heap verifications (see `VERIFY_INTERVAL`).
+    VerifyInterval,
+}
+
+/// The keys of the tunables in `RALLOC_CONF`.
+///
+/// This is indexed by `Param`.
+const KEYS: [&'static str; 17] = [
+    "memtrim_limit",
+    "memtrim_worthy",
+    "fragmentation_scale",
+    "local_memtrim_limit",
+    "local_memtrim_stop",
+    "local_memtrim_window",
+    "local_memtrim_max",
+    "brk_multiplier",
+    "brk_min_extra",
+    "brk_max_extra",
+    "brk_adaptive_max",
+    "brk_growth_window",
+    "fresh_multiplier",
+    "fresh_min_extra",
+    "fresh_max_extra",
+    "log_level",
+    "verify_interval",
+];
+
+/// The values of the tunables.
+///
+/// This is indexed by `Param`.
+static VALUES: [AtomicUsize; 17] = [
+    AtomicUsize::new(OS_MEMTRIM_LIMIT),
+    AtomicUsize::new(OS_MEMTRIM_WORTHY),
+    AtomicUsize::new(FRAGMENTATION_SCALE),
+    AtomicUsize::new(LOCAL_MEMTRIM_LIMIT),
+    AtomicUsize::new(LOCAL_MEMTRIM_STOP),
+    AtomicUsize::new(LOCAL_MEMTRIM_WINDOW),
+    AtomicUsize::new(LOCAL_MEMTRIM_MAX),
+    AtomicUsize::new(BRK_MULTIPLIER),
+    AtomicUsize::new(BRK_MIN_EXTRA),
+    AtomicUsize::new(BRK_MAX_EXTRA),
+    AtomicUsize::new(BRK_ADAPTIVE_MAX),
+    AtomicUsize::new(BRK_GROWTH_WINDOW),
+    AtomicUsize::new(FRESH_MULTIPLIER),
+    AtomicUsize::new(FRESH_MIN_EXTRA),
+    AtomicUsize::new(FRESH_MAX_EXTRA),
+    AtomicUsize::new(MIN_LOG_LEVEL as usize),
+    AtomicUsize::new(VERIFY_INTERVAL),
+];
+
+impl Param {
+    /// All the tunables.
+    const ALL: [Param; 17] = [
+        Param::MemtrimLimit,
+        Param::MemtrimWorthy,
+        Param::FragmentationScale,
+        Param::LocalMemtrimLimit,
+        Param::LocalMemtrimStop,
+        Param::LocalMemtrimWindow,
+        Param::LocalMemtrimMax,
+        Param::BrkMultiplier,
+        Param::BrkMinExtra,
+        Param::BrkMaxExtra,
+        Param::BrkAdaptiveMax,
+        Param::BrkGrowthWindow,
+        Param::FreshMultiplier,
+        Param::FreshMinExtra,
+        Param::FreshMaxExtra,
+        Param::LogLevel,
+        Param::VerifyInterval,
+    ];
+
+    /// Get the key of this tunable in `RALLOC_CONF`.
+    pub fn key(self) -> &'static str {
+        KEYS[self as usize]
+    }
+
+    /// Find the tunable with some key.
+    pub fn from_key(key: &[u8]) -> Option<Param> {
+        Param::ALL.iter().cloned().find(|x| x.key().as_bytes() == key)
+    }
+}
+
+/// Get the current value of a tunable.
+#[inline]
+pub fn get(param: Param) -> usize {
+    VALUES[param as usize].load(atomic::Ordering::Relaxed)
+}
+
+/// Set the value of a tunable.
+///
+/// This takes effect on the following allocator operations (in every thread). The old value is
+/// returned.
+#[inline]
+pub fn set(param: Param, value: usize) -> usize {
+    // Logging...
+    log!(NOTE, "Setting {} to {}.", param.key(), value);
+
+    VALUES[param as usize].swap(value, atomic::Ordering::Relaxed)
+}
@@ -0,0 +1,187 @@
+//! Heap dumps.
+//!
+//! This writes a snapshot of the block pools to a file descriptor on demand, which is useful for
+//! inspecting fragmentation. No memory is allocated while dumping.
+
+use prelude::*;
+
+use core::fmt::{self, Write};
+
+use shim::syscalls;
+
+use bookkeeper::Bookkeeper;
+use {allocator, brk};
+
+/// The format of a heap dump.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Plain text.
+    ///
+    /// Every free block is listed on its own line with its address, size and alignment, followed
+    /// by the totals of its bookkeeper. The gaps between the free blocks (i.e. memory in use or
+    /// owned by other bookkeepers) are listed in between.
+    Text,
+    /// Graphviz source.
+    ///
+    /// Every bookkeeper is rendered as a chain of its free segments and the holes between them.
+    /// This can be rendered to e.g. SVG through `dot -Tsvg`.
+    Graphviz,
+}
+
+/// A writer to a file descriptor.
+struct FdWriter {
+    /// The file descriptor.
+    fd: usize,
+}
+
+impl fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut buf = s.as_bytes();
+
+        while !buf.is_empty() {
+            let res = syscalls::write(self.fd, buf);
+
+            // Errors are returned as negated codes, which are larger than any valid count.
+            if res == 0 || res > buf.len() {
+                return Err(fmt::Error);
+            }
+
+            buf = &buf[res..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the address of a block.
+#[inline]
+fn addr(block: &Block) -> usize {
+    *Pointer::from(block.empty_left()) as usize
+}
+
+/// Get the alignment of an address.
+///
+/// This is the largest power of two dividing the address.
+#[inline]
+fn alignment(addr: usize) -> usize {
+    addr & addr.wrapping_neg()
+}
+
+/// Get the name of a bookkeeper.
+#[inline]
+fn name(global: bool) -> &'static str {
+    if global { "global" } else { "local" }
+}
+
+/// Dump a bookkeeper as text.
+fn text(out: &mut FdWriter, n: usize, bk: &Bookkeeper, global: bool) -> fmt::Result {
+    writeln!(out, "bookkeeper {} ({}):", n, name(global))?;
+
+    // The number of non-empty blocks.
+    let mut blocks = 0;
+    // The number of bytes in the gaps.
+    let mut gaps = 0;
+    // The end of the previous block.
+    let mut end: Option<usize> = None;
+
+    for block in bk.blocks().iter().filter(|x| !x.is_empty()) {
+        let start = addr(block);
+
+        if let Some(end) = end {
+            writeln!(out, "  gap {:#x}  size {}", end, start - end)?;
+            gaps += start - end;
+        }
+
+        writeln!(out, "  free {:#x}  size {}  align {}", start, block.size(), alignment(start))?;
+
+        blocks += 1;
+        end = Some(start + block.size());
+    }
+
+    writeln!(out, "  total: {} bytes free in {} blocks, {} bytes in gaps, {} of {} pool entries \
+             used", bk.total_bytes(), blocks, gaps, bk.len(), bk.capacity())
+}
+
+/// Dump a bookkeeper as a Graphviz cluster.
+fn graphviz(out: &mut FdWriter, n: usize, bk: &Bookkeeper, global: bool) -> fmt::Result {
+    writeln!(out, "    subgraph cluster_{} {{", n)?;
+    writeln!(out, "        label=\"bookkeeper {} ({}): {} bytes free\";", n, name(global),
+             bk.total_bytes())?;
+
+    // The index and end of the previous block.
+    let mut prev: Option<(usize, usize)> = None;
+
+    for (m, block) in bk.blocks().iter().enumerate().filter(|&(_, x)| !x.is_empty()) {
+        let start = addr(block);
+
+        if let Some((prev_m, end)) = prev {
+            writeln!(out, "        h{}_{} [label=\"hole\\n{} bytes\", fillcolor=lightgray];", n, m,
+                     start - end)?;
+            writeln!(out, "        b{}_{} -> h{}_{} -> b{}_{};", n, prev_m, n, m, n, m)?;
+        }
+
+        writeln!(out, "        b{}_{} [label=\"{:#x}\\n{} bytes\", fillcolor=palegreen];", n, m,
+                 start, block.size())?;
+
+        prev = Some((m, start + block.size()));
+    }
+
+    writeln!(out, "    }}")
+}
+
+/// Dump the heap to a file descriptor.
+///
+/// This writes the free blocks of the bookkeepers of the current thread and the global allocator,
+/// together with the program break, to `fd` in the given format.
+///
+/// No memory is allocated while dumping. Note that this locks the allocator, hence it must not be
+/// called from inside the allocator (e.g. a heap corruption handler), which might hold the lock.
+///
+/// # Errors
+///
+/// If writing to `fd` fails, an error is returned, and the dump might be incomplete.
+pub fn dump_heap(fd: usize, format: Format) -> fmt::Result {
+    // Logging.
+    log!(NOTE, "Dumping the heap to file descriptor {}.", fd);
+
+    let mut out = FdWriter {
+        fd: fd,
+    };
+
+    match format {
+        Format::Text => writeln!(out, "ralloc heap dump")?,
+        Format::Graphviz => {
+            writeln!(out, "digraph heap {{")?;
+            writeln!(out, "    rankdir=LR;")?;
+            writeln!(out, "    node [shape=box, style=filled];")?;
+        },
+    }
+
+    // The number of the bookkeeper.
+    let mut n = 0;
+    // The first error.
+    let mut res = Ok(());
+
+    allocator::for_each_bookkeeper(|bk, global| {
+        if res.is_ok() {
+            res = match format {
+                Format::Text => text(&mut out, n, bk, global),
+                Format::Graphviz => graphviz(&mut out, n, bk, global),
+            };
+        }
+
+        n += 1;
+    });
+    res?;
+
+    // Get the program break.
+    let brk = *brk::current_brk() as usize;
+
+    match format {
+        Format::Text => writeln!(out, "program break: {:#x}", brk),
+        Format::Graphviz => {
+            writeln!(out, "    brk [label=\"program break\\n{:#x}\", shape=plaintext];", brk)?;
+            writeln!(out, "}}")
+        },
+    }
+}
@@ -0,0 +1,217 @@
+//! Reentrancy handling.
+//!
+//! A thread can enter the allocator from inside the allocator, e.g. when a heap corruption handler
+//! or a debugger hook allocates. At that point, the local allocator of the thread is moved out, and
+//! the global allocator might be locked by the very same thread, so the nested call cannot be
+//! served by either of them (the lock is not reentrant).
+//!
+//! Instead, the threads keep track of how deep they are inside the allocator. Nested allocations
+//! are served from a small emergency arena, whose memory is never reused, and nested frees are
+//! deferred until the outermost call leaves the allocator.
+
+#[cfg(feature = "tls")]
+use core::cell::Cell;
+use core::sync::atomic::{self, AtomicUsize};
+
+use shim::config;
+
+#[cfg(feature = "tls")]
+use tls;
+
+/// The emergency arena.
+static mut ARENA: [u64; config::EMERGENCY_ARENA_SIZE / 8] = [0; config::EMERGENCY_ARENA_SIZE / 8];
+/// The number of bytes used in the emergency arena.
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "tls")]
+tls! {
+    /// The number of times, the current thread entered the allocator without leaving it.
+    static DEPTH: Cell<usize> = Cell::new(0);
+    /// The number of deferred frees of the current thread.
+    static DEFERRED_LEN: Cell<usize> = Cell::new(0);
+    /// The deferred frees (pointer and size) of the current thread.
+    static DEFERRED: Cell<[(usize, usize); config::DEFERRED_FREES]> =
+        Cell::new([(0, 0); config::DEFERRED_FREES]);
+}
+
+/// Get the start address of the emergency arena.
+#[inline]
+fn start() -> usize {
+    unsafe {
+        // Only the address is taken.
+        ARENA.as_ptr() as usize
+    }
+}
+
+/// Allocate a buffer from the emergency arena.
+///
+/// This is lock-free. `None` is returned if the arena is exhausted.
+pub fn alloc(size: usize, align: usize) -> Option<*mut u8> {
+    let start = start();
+    let align = if align == 0 { 1 } else { align };
+
+    let mut used = USED.load(atomic::Ordering::Relaxed);
+    loop {
+        // Align the next free byte.
+        let ptr = (start + used).checked_add(align - 1)? / align * align;
+        let new = ptr.checked_add(size)? - start;
+
+        // The end of the arena is never handed out, such that all the buffers (including
+        // zero-sized ones) are contained in it.
+        if new >= config::EMERGENCY_ARENA_SIZE {
+            return None;
+        }
+
+        match USED.compare_exchange_weak(used, new, atomic::Ordering::Relaxed,
+                                         atomic::Ordering::Relaxed) {
+            Ok(_) => return Some(ptr as *mut u8),
+            Err(x) => used = x,
+        }
+    }
+}
+
+/// Is the buffer starting at `ptr` from the emergency arena?
+///
+/// Such buffers must never be passed on to the bookkeepers.
+#[inline]
+pub fn owns(ptr: *mut u8) -> bool {
+    let start = start();
+
+    ptr as usize >= start && (ptr as usize) < start + config::EMERGENCY_ARENA_SIZE
+}
+
+/// Mark the current thread as being inside the allocator.
+///
+/// This nests, i.e. the thread is inside the allocator until every `enter` is paired with a
+/// `leave`.
+#[inline]
+pub fn enter() {
+    #[cfg(feature = "tls")]
+    DEPTH.with(|x| x.set(x.get() + 1));
+}
+
+/// Mark the current thread as having left the allocator (once).
+#[inline]
+pub fn leave() {
+    #[cfg(feature = "tls")]
+    DEPTH.with(|x| {
+        debug_assert!(x.get() != 0, "Leaving the allocator without entering it.");
+
+        x.set(x.get().saturating_sub(1));
+    });
+}
+
+/// Is the current thread inside the allocator?
+///
+/// Without `tls`, nested calls cannot be detected, and this always returns `false`.
+#[inline]
+pub fn entered() -> bool {
+    #[cfg(feature = "tls")]
+    {
+        DEPTH.with(|x| x.get() != 0)
+    }
+
+    #[cfg(not(feature = "tls"))]
+    {
+        false
+    }
+}
+
+/// Defer a free until the current thread leaves the allocator.
+///
+/// If too many frees are deferred already, `false` is returned, and the buffer is left alone.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+pub fn defer_free(ptr: *mut u8, size: usize) -> bool {
+    #[cfg(feature = "tls")]
+    {
+        DEFERRED_LEN.with(|len| {
+            if len.get() == config::DEFERRED_FREES {
+                return false;
+            }
+
+            DEFERRED.with(|deferred| {
+                let mut arr = deferred.get();
+                arr[len.get()] = (ptr as usize, size);
+                deferred.set(arr);
+            });
+            len.set(len.get() + 1);
+
+            true
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    {
+        false
+    }
+}
+
+/// Pop a deferred free of the current thread.
+#[inline]
+pub fn pop_deferred() -> Option<(*mut u8, usize)> {
+    #[cfg(feature = "tls")]
+    {
+        DEFERRED_LEN.with(|len| {
+            if len.get() == 0 {
+                return None;
+            }
+
+            len.set(len.get() - 1);
+            let (ptr, size) = DEFERRED.with(|deferred| deferred.get()[len.get()]);
+
+            Some((ptr as *mut u8, size))
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_alloc() {
+        let a = alloc(13, 1).unwrap();
+        let b = alloc(8, 64).unwrap();
+
+        assert!(owns(a));
+        assert!(owns(b));
+        assert_eq!(b as usize % 64, 0);
+        assert!(b as usize >= a as usize + 13);
+        assert!(!owns(&mut 0 as *mut u8));
+
+        assert!(alloc(config::EMERGENCY_ARENA_SIZE, 1).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_deferred() {
+        assert!(!entered());
+        enter();
+        assert!(entered());
+
+        let mut x = 0u8;
+        assert!(defer_free(&mut x, 1));
+        assert_eq!(pop_deferred(), Some((&mut x as *mut u8, 1)));
+        assert_eq!(pop_deferred(), None);
+
+        leave();
+        assert!(!entered());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_nested() {
+        enter();
+        enter();
+        leave();
+        assert!(entered());
+
+        leave();
+        assert!(!entered());
+    }
+}
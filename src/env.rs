@@ -0,0 +1,182 @@
+//! Reading the runtime configuration from the environment.
+//!
+//! The tunables of [`config`](../config/index.html) are overridden through the `RALLOC_CONF`
+//! environment variable, which is parsed when the allocator is initialized.
+
+use core::str;
+
+use shim::syscalls;
+use shim::config::{CONF_VAR, ENVIRON_PATH, log};
+
+use config::{self, Param};
+
+/// The maximal length of an environment entry, which can be parsed.
+const MAX_ENTRY: usize = 1024;
+
+/// Did a system call fail?
+///
+/// Failing system calls return a negated error code.
+#[inline]
+fn failed(res: usize) -> bool {
+    res > !0 - 4096
+}
+
+/// Report an invalid item of the configuration to the log target.
+#[cold]
+fn report(msg: &str, item: &[u8]) {
+    log("ralloc: ");
+    log(msg);
+    log(": '");
+    log(str::from_utf8(item).unwrap_or("<invalid UTF-8>"));
+    log("'\n");
+}
+
+/// Parse a size.
+///
+/// This is a decimal number, optionally suffixed by `k`, `m` or `g` (binary units).
+fn parse_size(s: &[u8]) -> Option<usize> {
+    let (digits, shift) = match s.last() {
+        Some(&b'k') | Some(&b'K') => (&s[..s.len() - 1], 10),
+        Some(&b'm') | Some(&b'M') => (&s[..s.len() - 1], 20),
+        Some(&b'g') | Some(&b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+
+    if digits.is_empty() {
+        return None;
+    }
+
+    let mut res: usize = 0;
+    for &b in digits {
+        if b < b'0' || b > b'9' {
+            return None;
+        }
+
+        res = res.checked_mul(10)?.checked_add((b - b'0') as usize)?;
+    }
+
+    res.checked_mul(1 << shift)
+}
+
+/// Apply a configuration string.
+///
+/// The string has the same format as `RALLOC_CONF`, i.e. comma-separated `key=value` items. Unknown
+/// keys and invalid values are reported and skipped.
+pub fn apply(conf: &[u8]) {
+    for item in conf.split(|&b| b == b',').filter(|x| !x.is_empty()) {
+        let mut split = item.splitn(2, |&b| b == b'=');
+        let key = split.next().unwrap_or(&[]);
+
+        let value = match split.next() {
+            Some(value) => value,
+            None => {
+                report("missing value in RALLOC_CONF", item);
+                continue;
+            },
+        };
+
+        let param = match Param::from_key(key) {
+            Some(param) => param,
+            None if key == b"arenas" => {
+                report("ralloc has no arenas, ignoring key in RALLOC_CONF", key);
+                continue;
+            },
+            None => {
+                report("unknown key in RALLOC_CONF", key);
+                continue;
+            },
+        };
+
+        match parse_size(value) {
+            Some(value) => {
+                config::set(param, value);
+            },
+            None => report("invalid value in RALLOC_CONF", item),
+        }
+    }
+}
+
+/// Handle an entry of the environment.
+fn handle_entry(entry: &[u8], truncated: bool) {
+    if entry.len() > CONF_VAR.len() && entry.starts_with(CONF_VAR) && entry[CONF_VAR.len()] == b'=' {
+        if truncated {
+            report("RALLOC_CONF is too long, ignoring it", CONF_VAR);
+        } else {
+            apply(&entry[CONF_VAR.len() + 1..]);
+        }
+    }
+}
+
+/// Initialize the runtime configuration from the environment.
+///
+/// This reads `RALLOC_CONF` from the environment of the process without allocating, by streaming
+/// the environment file of the shim through a fixed buffer. This is called once, when the global
+/// allocator is initialized, but can be called again to reset the tunables set in the environment.
+pub fn init() {
+    let fd = syscalls::open_read(ENVIRON_PATH);
+    if failed(fd) {
+        log!(NOTE, "Unable to read the environment. Using the default configuration.");
+
+        return;
+    }
+
+    let mut chunk = [0; 256];
+    let mut buf = [0; MAX_ENTRY];
+    let mut len = 0;
+    let mut truncated = false;
+
+    loop {
+        let n = syscalls::read(fd, &mut chunk);
+        if n == 0 || failed(n) { break; }
+
+        for &b in &chunk[..n] {
+            if b == 0 {
+                // The entry is over.
+                handle_entry(&buf[..len], truncated);
+
+                len = 0;
+                truncated = false;
+            } else if len < MAX_ENTRY {
+                buf[len] = b;
+                len += 1;
+            } else {
+                truncated = true;
+            }
+        }
+    }
+
+    // Handle the last entry, if it isn't terminated.
+    if len != 0 {
+        handle_entry(&buf[..len], truncated);
+    }
+
+    syscalls::close(fd);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size(b"0"), Some(0));
+        assert_eq!(parse_size(b"1234"), Some(1234));
+        assert_eq!(parse_size(b"4k"), Some(4096));
+        assert_eq!(parse_size(b"2M"), Some(2 << 20));
+        assert_eq!(parse_size(b""), None);
+        assert_eq!(parse_size(b"k"), None);
+        assert_eq!(parse_size(b"12x"), None);
+        assert_eq!(parse_size(b"99999999999999999999999"), None);
+    }
+
+    #[test]
+    fn test_apply() {
+        let old = config::get(Param::FreshMaxExtra);
+
+        apply(b"fresh_max_extra=2k,,unknown=4,arenas=2,fresh_min_extra");
+        assert_eq!(config::get(Param::FreshMaxExtra), 2048);
+        assert_eq!(config::set(Param::FreshMaxExtra, 4096), 2048);
+
+        config::set(Param::FreshMaxExtra, old);
+    }
+}
@@ -3,38 +3,142 @@
 use prelude::*;
 
 use core::sync::atomic::{self, AtomicPtr};
-use core::mem;
+use core::{mem, fmt, intrinsics};
 
 use shim::config;
 
+use stats::{self, Stats};
+
 #[cfg(feature = "tls")]
 use tls;
 
 /// The global OOM handler.
 static OOM_HANDLER: AtomicPtr<()> = AtomicPtr::new(config::default_oom_handler as *mut ());
+/// The global OOM handler receiving context (if any).
+static OOM_INFO_HANDLER: AtomicPtr<()> = AtomicPtr::new(0 as *mut ());
+/// The heap corruption handler.
+static CORRUPTION_HANDLER: AtomicPtr<()> = AtomicPtr::new(default_corruption_handler as *mut ());
 #[cfg(feature = "tls")]
 tls! {
     /// The thread-local OOM handler.
     static THREAD_OOM_HANDLER: MoveCell<Option<fn() -> !>> = MoveCell::new(None);
+    /// The thread-local OOM handler receiving context (if any).
+    static THREAD_OOM_INFO_HANDLER: MoveCell<Option<fn(&OomInfo) -> OomAction>> = MoveCell::new(None);
 }
 
-/// Call the OOM handler.
+/// An allocation error.
 ///
-/// This is used one out-of-memory errors, and will never return. Usually, it simply consists
-/// of aborting the process.
+/// This is returned by the fallible allocation functions (e.g. `try_alloc`), instead of calling
+/// the OOM handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    /// The system is unable to provide the memory.
+    OutOfMemory,
+    /// The size of the request (including alignment and bookkeeping) overflows.
+    SizeOverflow,
+    /// The requested alignment is invalid (zero).
+    InvalidAlignment,
+    /// The request would exceed the heap limit or the thread limit.
+    LimitExceeded,
+    /// The buffer was rejected by the heap corruption handler.
+    ///
+    /// This can only happen when compiled with `validate`.
+    InvalidBuffer,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AllocError::OutOfMemory => write!(f, "out of memory"),
+            AllocError::SizeOverflow => write!(f, "allocation size overflows"),
+            AllocError::InvalidAlignment => write!(f, "invalid alignment"),
+            AllocError::LimitExceeded => write!(f, "memory limit exceeded"),
+            AllocError::InvalidBuffer => write!(f, "invalid buffer"),
+        }
+    }
+}
+
+/// Information about an out-of-memory condition.
 ///
-/// # An important note
+/// This is passed to the OOM handlers set through `set_oom_info_handler` and
+/// `set_thread_oom_info_handler`.
+#[derive(Clone, Copy, Debug)]
+pub struct OomInfo {
+    /// The requested size.
+    pub size: usize,
+    /// The requested alignment.
+    pub align: usize,
+    /// The error, which caused the request to fail.
+    pub error: AllocError,
+    /// Was the request served by the global allocator (rather than a thread-local allocator)?
+    pub global: bool,
+    /// The heap statistics at the time of the failure.
+    pub stats: Stats,
+}
+
+impl OomInfo {
+    /// Describe a failed request, taking a snapshot of the heap statistics.
+    #[inline]
+    pub fn new(size: usize, align: usize, error: AllocError, global: bool) -> OomInfo {
+        OomInfo {
+            size: size,
+            align: align,
+            error: error,
+            global: global,
+            stats: stats::stats(),
+        }
+    }
+}
+
+/// The action requested by an OOM handler receiving context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OomAction {
+    /// Retry the request.
+    ///
+    /// This is useful after having freed memory (e.g. application caches). The request is retried
+    /// at most `OOM_RETRIES` (as defined in the shim) times.
+    Retry,
+    /// Give up, and pass the condition on to the next handler.
+    Fail,
+}
+
+/// Ask the OOM handlers receiving context, whether the request should be retried.
 ///
-/// This is for OOM-conditions, not malformed or too big allocations, but when the system is unable
-/// to gather memory for the allocation (SBRK fails).
+/// The thread-local handler takes precedence over the global one.
+fn retry_requested(info: &OomInfo) -> bool {
+    // If TLS is enabled, we will ask the thread-local handler first.
+    #[cfg(feature = "tls")]
+    {
+        if let Some(handler) = THREAD_OOM_INFO_HANDLER.with(|x| x.get()) {
+            log!(DEBUG, "Calling the local OOM handler with context.");
+
+            if handler(info) == OomAction::Retry {
+                return true;
+            }
+        }
+    }
+
+    let handler = OOM_INFO_HANDLER.load(atomic::Ordering::SeqCst);
+    if handler.is_null() {
+        return false;
+    }
+
+    log!(DEBUG, "Calling the global OOM handler with context.");
+
+    unsafe {
+        // Transmute the atomic pointer to a function pointer and call it.
+        (mem::transmute::<_, fn(&OomInfo) -> OomAction>(handler))(info) == OomAction::Retry
+    }
+}
+
+/// Call the diverging OOM handlers.
 ///
-/// The rule of thumb is that this should be called, if and only if unwinding (which allocates)
-/// will hit the same error.
-pub fn oom() -> ! {
+/// The thread-local handler takes precedence over the global one.
+fn diverge() -> ! {
     // If TLS is enabled, we will use the thread-local OOM.
     #[cfg(feature = "tls")]
     {
-        if let Some(handler) = THREAD_OOM_HANDLER.with(|x| x.replace(None)) {
+        if let Some(handler) = THREAD_OOM_HANDLER.with(|x| x.get()) {
             log!(DEBUG, "Calling the local OOM handler.");
 
             handler();
@@ -51,9 +155,65 @@ pub fn oom() -> ! {
     }
 }
 
+/// Call the OOM handlers, retrying the request if asked to.
+///
+/// This is used on out-of-memory errors. First, the handlers receiving context are called, and if
+/// one of them returns `OomAction::Retry`, `retry` is called. This is repeated at most
+/// `OOM_RETRIES` times. If the request still fails, the diverging handlers are called, which
+/// usually consists of aborting the process.
+///
+/// # An important note
+///
+/// This is for OOM-conditions, not malformed or too big allocations, but when the system is unable
+/// to gather memory for the allocation (SBRK fails).
+///
+/// The rule of thumb is that this should be called, if and only if unwinding (which allocates)
+/// will hit the same error.
+///
+/// No allocator lock may be held when calling this, since the handlers (and `retry`) might
+/// allocate or free.
+pub fn oom<T, F>(mut info: OomInfo, mut retry: F) -> T
+    where F: FnMut() -> Result<T, AllocError> {
+    log!(WARNING, "Out of memory ({}) while requesting {} bytes.", info.error, info.size);
+
+    for attempt in 0..config::OOM_RETRIES {
+        if !retry_requested(&info) { break; }
+
+        log!(NOTE, "Retrying the request (attempt {}).", attempt + 1);
+
+        match retry() {
+            Ok(res) => return res,
+            Err(err) => {
+                // Update the description for the next round.
+                info.error = err;
+                info.stats = stats::stats();
+            },
+        }
+    }
+
+    diverge()
+}
+
+/// Call the OOM handlers, without being able to retry.
+///
+/// This is used where the request cannot be retried (e.g. in the internals of the allocator). If
+/// a handler asks for a retry, a warning is emitted, and the diverging handlers are called.
+///
+/// See [`oom`](./fn.oom.html) for more information.
+#[cold]
+pub fn oom_fatal(info: &OomInfo) -> ! {
+    log!(WARNING, "Out of memory ({}) while requesting {} bytes.", info.error, info.size);
+
+    if retry_requested(info) {
+        log!(WARNING, "The OOM handler asked for a retry, which is not possible here.");
+    }
+
+    diverge()
+}
+
 /// Set the OOM handler.
 ///
-/// This is called when the process is out-of-memory.
+/// This is called when the process is out-of-memory, after the handlers receiving context.
 #[inline]
 pub fn set_oom_handler(handler: fn() -> !) {
     // Logging...
@@ -62,11 +222,22 @@ pub fn set_oom_handler(handler: fn() -> !) {
     OOM_HANDLER.store(handler as *mut (), atomic::Ordering::SeqCst);
 }
 
-/// Override the OOM handler for the current thread.
+/// Set the OOM handler receiving context.
 ///
-/// # Panics
+/// This is called when the process is out-of-memory, and gets a description of the failed
+/// request. It can either return `OomAction::Retry` (e.g. after freeing caches), `OomAction::Fail`
+/// to pass the condition on to the diverging handler, or diverge itself.
+#[inline]
+pub fn set_oom_info_handler(handler: fn(&OomInfo) -> OomAction) {
+    // Logging...
+    log!(NOTE, "Setting the global OOM handler with context.");
+
+    OOM_INFO_HANDLER.store(handler as *mut (), atomic::Ordering::SeqCst);
+}
+
+/// Override the OOM handler for the current thread.
 ///
-/// This might panic if a thread OOM handler already exists.
+/// The handler stays in place until it is overridden.
 #[inline]
 #[cfg(feature = "tls")]
 pub fn set_thread_oom_handler(handler: fn() -> !) {
@@ -84,10 +255,236 @@ pub fn set_thread_oom_handler(handler: fn() -> !) {
     });
 }
 
+/// Override the OOM handler receiving context for the current thread.
+///
+/// This takes precedence over the global handler receiving context. See
+/// [`set_oom_info_handler`](./fn.set_oom_info_handler.html) for more information.
+#[inline]
+#[cfg(feature = "tls")]
+pub fn set_thread_oom_info_handler(handler: fn(&OomInfo) -> OomAction) {
+    // Logging...
+    log!(NOTE, "Setting the thread OOM handler with context.");
+
+    THREAD_OOM_INFO_HANDLER.with(|thread_oom| {
+        // Replace it with the new handler.
+        let res = thread_oom.replace(Some(handler));
+
+        // Throw a warning if it overrides another handler.
+        if res.is_some() {
+            log!(WARNING, "An old thread OOM handler with context was overriden.");
+        }
+    });
+}
+
+/// A detected heap corruption.
+///
+/// This is passed to the heap corruption handler and describes the misuse of the allocator, which
+/// was detected.
+#[derive(Clone, Copy, Debug)]
+pub enum Corruption {
+    /// A freed buffer overlaps with an already free block.
+    ///
+    /// This is usually caused by a double free.
+    OverlappingFree {
+        /// The pointer to the freed buffer.
+        ptr: *const u8,
+        /// The size of the freed buffer.
+        size: usize,
+        /// The pointer to the free block, which the buffer overlaps with.
+        free_ptr: *const u8,
+        /// The size of the free block, which the buffer overlaps with.
+        free_size: usize,
+    },
+    /// A freed or reallocated buffer lies outside the memory owned by ralloc.
+    ///
+    /// This is usually caused by freeing a stack buffer, or a buffer from another allocator.
+    ForeignFree {
+        /// The pointer to the buffer.
+        ptr: *const u8,
+        /// The size of the buffer.
+        size: usize,
+    },
+    /// A freed or reallocated buffer straddles multiple segments owned by ralloc.
+    StraddlingFree {
+        /// The pointer to the buffer.
+        ptr: *const u8,
+        /// The size of the buffer.
+        size: usize,
+    },
+    /// A free block was modified before being handed out again.
+    ///
+    /// This is detected through the poison pattern, and usually caused by writing through a
+    /// dangling pointer. As nothing can be skipped, the allocation proceeds if the handler returns.
+    UseAfterFree {
+        /// The pointer to the recycled block.
+        ptr: *const u8,
+        /// The offset of the first modified byte.
+        offset: usize,
+    },
+    /// A block pool is inconsistent.
+    ///
+    /// This is detected by the periodic heap verification (see `Param::VerifyInterval`).
+    InconsistentHeap(HeapError),
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Corruption::OverlappingFree { ptr, size, free_ptr, free_size } => {
+                write!(f, "freeing 0x{:x}[{}], which overlaps with the free block 0x{:x}[{}] \
+                       (double free?)", ptr as usize, size, free_ptr as usize, free_size)
+            },
+            Corruption::ForeignFree { ptr, size } => {
+                write!(f, "freeing 0x{:x}[{}], which was not allocated by ralloc", ptr as usize,
+                       size)
+            },
+            Corruption::StraddlingFree { ptr, size } => {
+                write!(f, "freeing 0x{:x}[{}], which straddles multiple segments", ptr as usize,
+                       size)
+            },
+            Corruption::UseAfterFree { ptr, offset } => {
+                write!(f, "the free block 0x{:x} was modified at offset {} (use after free?)",
+                       ptr as usize, offset)
+            },
+            Corruption::InconsistentHeap(err) => write!(f, "the heap is inconsistent: {}", err),
+        }
+    }
+}
+
+/// An inconsistency in a block pool.
+///
+/// This is returned by [`verify_heap`](./fn.verify_heap.html), and always indicates a heap
+/// corruption (or a bug in ralloc).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapError {
+    /// The block at some index is placed before its left neighbor.
+    Unsorted {
+        /// The index of the block.
+        index: usize,
+    },
+    /// The block at some index is adjacent to its right neighbor, without them being merged.
+    Adjacent {
+        /// The index of the block.
+        index: usize,
+    },
+    /// The empty block at some index is not placed at the address of its right neighbor, or it is
+    /// trailing.
+    MisplacedEmpty {
+        /// The index of the block.
+        index: usize,
+    },
+    /// The sum of the block sizes is not the recorded byte count.
+    ByteCount {
+        /// The sum of the block sizes.
+        counted: usize,
+        /// The recorded byte count.
+        recorded: usize,
+    },
+    /// The pool has not enough capacity left for reserving.
+    Capacity {
+        /// The length of the pool.
+        len: usize,
+        /// The capacity of the pool.
+        capacity: usize,
+    },
+    /// A block extends past the program break.
+    PastBreak {
+        /// The pointer to the block.
+        ptr: *const u8,
+        /// The size of the block.
+        size: usize,
+        /// The program break.
+        brk: *const u8,
+    },
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeapError::Unsorted { index } => {
+                write!(f, "the block pool is not sorted at index {}", index)
+            },
+            HeapError::Adjacent { index } => write!(f, "adjacent blocks at index {}", index),
+            HeapError::MisplacedEmpty { index } => {
+                write!(f, "misplaced empty block at index {}", index)
+            },
+            HeapError::ByteCount { counted, recorded } => {
+                write!(f, "the blocks sum to {} bytes, but {} were recorded", counted, recorded)
+            },
+            HeapError::Capacity { len, capacity } => {
+                write!(f, "the pool has capacity {} for length {}", capacity, len)
+            },
+            HeapError::PastBreak { ptr, size, brk } => {
+                write!(f, "the free block 0x{:x}[{}] extends past the program break 0x{:x}",
+                       ptr as usize, size, brk as usize)
+            },
+        }
+    }
+}
+
+/// A writer to the shim's log target.
+///
+/// In contrast to the logger, this is always available, and is used for reporting fatal errors.
+struct LogTarget;
+
+impl fmt::Write for LogTarget {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if config::log(s) == !0 { Err(fmt::Error) } else { Ok(()) }
+    }
+}
+
+/// The default heap corruption handler.
+///
+/// This reports the corruption and aborts the process.
+#[cold]
+fn default_corruption_handler(corruption: Corruption) {
+    use core::fmt::Write;
+
+    let _ = writeln!(LogTarget, "\x1b[31;1mHeap corruption detected: {}. Aborting.\x1b[m",
+                     corruption);
+
+    unsafe {
+        intrinsics::abort();
+    }
+}
+
+/// Call the heap corruption handler.
+///
+/// If the handler returns, the operation that triggered it must be skipped (if possible), such
+/// that the internal state of the allocator is kept intact. This usually means leaking the
+/// offending buffer.
+#[cold]
+pub fn corruption(corruption: Corruption) {
+    log!(ERROR, "Heap corruption detected: {}.", corruption);
+
+    unsafe {
+        // Transmute the atomic pointer to a function pointer and call it.
+        (mem::transmute::<_, fn(Corruption)>(CORRUPTION_HANDLER.load(atomic::Ordering::SeqCst)))
+            (corruption)
+    }
+}
+
+/// Set the heap corruption handler.
+///
+/// This is called when misuse of the allocator (e.g. double free) is detected. The default
+/// handler aborts the process.
+///
+/// If the handler returns, the offending operation is skipped, leaking the involved buffer rather
+/// than corrupting the allocator.
+#[inline]
+pub fn set_corruption_handler(handler: fn(Corruption)) {
+    // Logging...
+    log!(NOTE, "Setting the heap corruption handler.");
+
+    CORRUPTION_HANDLER.store(handler as *mut (), atomic::Ordering::SeqCst);
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use shim::config;
+
     #[test]
     #[should_panic]
     fn test_panic_oom() {
@@ -96,7 +493,7 @@ mod test {
         }
 
         set_oom_handler(panic);
-        oom();
+        oom_fatal(&OomInfo::new(16, 8, AllocError::OutOfMemory, true));
     }
 
     #[test]
@@ -113,6 +510,67 @@ mod test {
 
         set_oom_handler(infinite);
         set_thread_oom_handler(panic);
-        oom();
+        oom_fatal(&OomInfo::new(16, 8, AllocError::OutOfMemory, true));
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_thread_oom_retry() {
+        fn retry(info: &OomInfo) -> OomAction {
+            assert_eq!(info.size, 16);
+            assert_eq!(info.align, 8);
+
+            OomAction::Retry
+        }
+
+        set_thread_oom_info_handler(retry);
+
+        let mut attempts = 0;
+        let res = oom(OomInfo::new(16, 8, AllocError::OutOfMemory, false), || {
+            attempts += 1;
+            if attempts < 3 { Err(AllocError::OutOfMemory) } else { Ok(42) }
+        });
+
+        assert_eq!(res, 42);
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(feature = "tls")]
+    fn test_panic_thread_oom_retry_bound() {
+        fn retry(_: &OomInfo) -> OomAction {
+            OomAction::Retry
+        }
+        fn panic() -> ! {
+            panic!("cats are not cute.");
+        }
+
+        set_thread_oom_info_handler(retry);
+        set_thread_oom_handler(panic);
+
+        let mut attempts = 0;
+        oom::<(), _>(OomInfo::new(16, 8, AllocError::OutOfMemory, false), || {
+            attempts += 1;
+            assert!(attempts <= config::OOM_RETRIES);
+
+            Err(AllocError::OutOfMemory)
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_panic_corruption() {
+        fn panic(_: Corruption) {
+            panic!("cats are not cute.");
+        }
+
+        set_corruption_handler(panic);
+        corruption(Corruption::OverlappingFree {
+            ptr: 0x1000 as *const u8,
+            size: 16,
+            free_ptr: 0x1008 as *const u8,
+            free_size: 16,
+        });
     }
 }
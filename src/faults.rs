@@ -0,0 +1,119 @@
+//! Fault injection.
+//!
+//! When compiled with `fault_injection`, acquisitions from the memory source (i.e. BRK) can be made
+//! to fail deterministically, in order to exercise the OOM paths of the allocator and the
+//! application:
+//!
+//! ```rust,ignore
+//! // Let the next two acquisitions succeed, and fail the rest.
+//! ralloc::faults::fail_after(2);
+//! ```
+//!
+//! Injected failures are reported as `AllocError::OutOfMemory`, exactly like a failing memory
+//! source.
+
+use core::sync::atomic::{self, AtomicUsize};
+
+use {allocator, verify};
+use fail::AllocError;
+
+/// The number of acquisitions since `fail_after` was called.
+static AFTER_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The number of acquisitions since `fail_every` was called.
+static EVERY_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The number of failures injected.
+static INJECTED: AtomicUsize = AtomicUsize::new(0);
+/// The number of acquisitions succeeding, before every acquisition fails.
+static FAIL_AFTER: AtomicUsize = AtomicUsize::new(!0);
+/// The period of failing acquisitions (zero if none).
+static FAIL_EVERY: AtomicUsize = AtomicUsize::new(0);
+/// The size of the largest acquisition, which can succeed.
+static FAIL_SIZES_ABOVE: AtomicUsize = AtomicUsize::new(!0);
+
+/// Make every acquisition fail, after the next `n` ones.
+///
+/// This starts counting the acquisitions over. The count of `fail_every` is kept, such that the
+/// two compose.
+pub fn fail_after(n: usize) {
+    // Logging...
+    log!(NOTE, "Injecting failures after {} acquisitions.", n);
+
+    AFTER_COUNT.store(0, atomic::Ordering::SeqCst);
+    FAIL_AFTER.store(n, atomic::Ordering::SeqCst);
+}
+
+/// Make every `k`-th acquisition fail.
+///
+/// Zero disables this. This starts counting the acquisitions over. The count of `fail_after` is
+/// kept, such that the two compose.
+pub fn fail_every(k: usize) {
+    // Logging...
+    log!(NOTE, "Injecting failures every {} acquisitions.", k);
+
+    EVERY_COUNT.store(0, atomic::Ordering::SeqCst);
+    FAIL_EVERY.store(k, atomic::Ordering::SeqCst);
+}
+
+/// Make every acquisition of more than `bytes` bytes fail.
+///
+/// Note that the memory source acquires more than requested (see `extra_brk`).
+pub fn fail_sizes_above(bytes: usize) {
+    // Logging...
+    log!(NOTE, "Injecting failures for acquisitions above {} bytes.", bytes);
+
+    FAIL_SIZES_ABOVE.store(bytes, atomic::Ordering::SeqCst);
+}
+
+/// Stop injecting failures.
+///
+/// This resets the counters as well.
+pub fn reset() {
+    // Logging...
+    log!(NOTE, "Resetting the fault injection.");
+
+    FAIL_AFTER.store(!0, atomic::Ordering::SeqCst);
+    FAIL_EVERY.store(0, atomic::Ordering::SeqCst);
+    FAIL_SIZES_ABOVE.store(!0, atomic::Ordering::SeqCst);
+    AFTER_COUNT.store(0, atomic::Ordering::SeqCst);
+    EVERY_COUNT.store(0, atomic::Ordering::SeqCst);
+    INJECTED.store(0, atomic::Ordering::SeqCst);
+}
+
+/// Get the number of failures injected since the last reset.
+pub fn injected() -> usize {
+    INJECTED.load(atomic::Ordering::SeqCst)
+}
+
+/// Check the consistency of the allocator.
+///
+/// This verifies the block pools of the current thread and the global allocator (see
+/// `verify_heap`), and panics if they are inconsistent. In debug mode, the (more expensive)
+/// consistency checks of the bookkeepers are run as well.
+pub fn check_consistency() {
+    allocator::check();
+
+    if let Err(err) = verify::verify_heap() {
+        panic!("The allocator is inconsistent: {:?}", err);
+    }
+}
+
+/// Decide if an acquisition of `size` bytes fails.
+///
+/// This is called by the memory source, before acquiring memory.
+pub fn inject(size: usize) -> Result<(), AllocError> {
+    let after = AFTER_COUNT.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+    let n = EVERY_COUNT.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+    let every = FAIL_EVERY.load(atomic::Ordering::SeqCst);
+
+    if after > FAIL_AFTER.load(atomic::Ordering::SeqCst) || (every != 0 && n % every == 0)
+        || size > FAIL_SIZES_ABOVE.load(atomic::Ordering::SeqCst) {
+        // Logging...
+        log!(WARNING, "Injecting failure to acquire {} bytes.", size);
+
+        INJECTED.fetch_add(1, atomic::Ordering::SeqCst);
+
+        Err(AllocError::OutOfMemory)
+    } else {
+        Ok(())
+    }
+}
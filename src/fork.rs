@@ -0,0 +1,110 @@
+//! Fork safety.
+//!
+//! If another thread holds one of the allocator locks while forking, the lock stays held forever
+//! in the child, which then deadlocks on its first allocation. To avoid this, `prefork` acquires
+//! every lock before forking, and `postfork_parent`/`postfork_child` release them afterwards.
+//!
+//! These can either be called around `fork` manually (e.g. by a C shim), or registered through
+//! `register_fork_handlers`.
+
+use {allocator, brk, pressure};
+#[cfg(feature = "validate")]
+use owned;
+#[cfg(not(feature = "tls"))]
+use random;
+#[cfg(feature = "simulation")]
+use sim;
+#[cfg(all(feature = "log", not(feature = "no_log_lock")))]
+use log;
+
+use shim;
+
+/// Acquire all the allocator locks before forking.
+///
+/// The locks are taken in the order, they are nested in the allocator, such that this cannot
+/// deadlock.
+///
+/// # Safety
+///
+/// This must be followed by either `postfork_parent` or `postfork_child`. Until then, any
+/// allocation deadlocks.
+pub unsafe extern fn prefork() {
+    // Logging...
+    log!(NOTE, "Preparing to fork.");
+
+    allocator::fork_lock();
+    brk::fork_lock();
+    #[cfg(feature = "simulation")]
+    sim::fork_lock();
+    #[cfg(feature = "validate")]
+    owned::fork_lock();
+    pressure::fork_lock();
+    #[cfg(not(feature = "tls"))]
+    random::fork_lock();
+    #[cfg(all(feature = "log", not(feature = "no_log_lock")))]
+    log::internal::fork_lock();
+}
+
+/// Release the allocator locks.
+///
+/// This is done in the reverse order of `prefork`.
+unsafe fn release(child: bool) {
+    #[cfg(all(feature = "log", not(feature = "no_log_lock")))]
+    log::internal::fork_unlock();
+    #[cfg(not(feature = "tls"))]
+    random::fork_unlock();
+    pressure::fork_unlock();
+    #[cfg(feature = "validate")]
+    owned::fork_unlock();
+    #[cfg(feature = "simulation")]
+    sim::fork_unlock();
+    brk::fork_unlock(child);
+    allocator::fork_unlock();
+}
+
+/// Release the allocator locks in the parent after forking.
+///
+/// # Safety
+///
+/// This must be preceded by `prefork`.
+pub unsafe extern fn postfork_parent() {
+    release(false);
+
+    // Logging...
+    log!(NOTE, "Forked (parent).");
+}
+
+/// Reinitialize the allocator locks in the child after forking.
+///
+/// Since the child only contains the forking thread, which holds every lock, they are simply
+/// released. Furthermore, the cached program break is reset.
+///
+/// # Safety
+///
+/// This must be preceded by `prefork`.
+pub unsafe extern fn postfork_child() {
+    release(true);
+
+    // Logging...
+    log!(NOTE, "Forked (child).");
+}
+
+/// Register `prefork`, `postfork_parent` and `postfork_child` as fork handlers.
+///
+/// This uses `pthread_atfork`, if available. Note that this should be called outside the
+/// allocator (e.g. not from an OOM handler).
+///
+/// # Errors
+///
+/// If the handlers cannot be registered (e.g. `pthread_atfork` is not available), an error is
+/// returned.
+pub fn register_fork_handlers() -> Result<(), ()> {
+    // Logging...
+    log!(NOTE, "Registering the fork handlers.");
+
+    if shim::fork::register(prefork, postfork_parent, postfork_child) {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
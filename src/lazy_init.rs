@@ -53,6 +53,17 @@ impl<F: FnMut() -> T, T> LazyInit<F, T> {
         }
     }
 
+    /// Get a mutable reference to the inner value, if it is initialized.
+    ///
+    /// In contrast to `get`, this never initializes the value.
+    #[inline]
+    pub fn get_initialized(&mut self) -> Option<&mut T> {
+        match self.state {
+            State::Initialized(ref mut x) => Some(x),
+            State::Uninitialized(_) => None,
+        }
+    }
+
     /// Get the inner of the container.
     ///
     /// This won't mutate the container itself, since it consumes it. The initializer will (if
@@ -76,7 +87,9 @@ mod test {
     fn test_init() {
         let mut lazy = LazyInit::new(|| 300);
 
+        assert!(lazy.get_initialized().is_none());
         assert_eq!(*lazy.get(), 300);
+        assert_eq!(lazy.get_initialized().cloned(), Some(300));
         *lazy.get() = 400;
         assert_eq!(*lazy.get(), 400);
     }
@@ -17,13 +17,15 @@
 
 #![feature(allocator, const_fn, core_intrinsics, stmt_expr_attributes, drop_types_in_const,
            nonzero, optin_builtin_traits, type_ascription, thread_local, linkage,
-           try_from)]
+           try_from, integer_atomics)]
 #![warn(missing_docs, cast_precision_loss, cast_sign_loss, cast_possible_wrap,
         cast_possible_truncation, filter_map, if_not_else, items_after_statements,
         invalid_upcast_comparisons, mutex_integer, nonminimal_bool, shadow_same, shadow_unrelated,
         single_match_else, string_add, string_add_assign, wrong_pub_self_convention)]
 
 extern crate ralloc_shim as shim;
+#[cfg(test)]
+extern crate std;
 
 #[macro_use]
 mod log;
@@ -41,16 +43,50 @@ mod block;
 mod bookkeeper;
 mod brk;
 mod cell;
+pub mod config;
+mod dump;
+mod emergency;
+mod env;
 mod fail;
+#[cfg(feature = "fault_injection")]
+pub mod faults;
+mod fork;
 mod lazy_init;
 mod leak;
+mod limit;
+#[cfg(test)]
+mod model;
+#[cfg(feature = "validate")]
+mod owned;
+#[cfg(feature = "tls")]
+mod policy;
 mod prelude;
+mod pressure;
 mod ptr;
+#[cfg(feature = "quarantine")]
+mod quarantine;
+mod random;
+#[cfg(any(test, feature = "simulation"))]
+pub mod sim;
+mod stats;
 mod sync;
 mod vec;
+mod verify;
 
-pub use allocator::{alloc, free, realloc, realloc_inplace};
+pub use allocator::{alloc, compact, free, realloc, realloc_inplace, try_alloc, try_alloc_zeroed,
+                    try_realloc, trim, trim_thread, TrimStats};
 pub use brk::sbrk;
-pub use fail::set_oom_handler;
+pub use dump::{dump_heap, Format};
+pub use fork::{prefork, postfork_parent, postfork_child, register_fork_handlers};
+pub use fail::{set_oom_handler, set_oom_info_handler, set_corruption_handler, AllocError, Corruption,
+               HeapError, OomInfo, OomAction};
+pub use limit::{set_heap_limit, heap_limit};
+pub use pressure::{add_pressure_callback, remove_pressure_callback, add_high_water_mark,
+                   clear_high_water_marks, Pressure};
+pub use random::set_random_seed;
+pub use stats::{stats, Stats};
+pub use verify::verify_heap;
+#[cfg(feature = "tls")]
+pub use fail::{set_thread_oom_handler, set_thread_oom_info_handler};
 #[cfg(feature = "tls")]
-pub use fail::set_thread_oom_handler;
+pub use limit::{set_thread_limit, thread_limit, thread_usage};
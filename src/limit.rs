@@ -0,0 +1,153 @@
+//! Memory quotas.
+//!
+//! This allows capping the memory usage of the process (by limiting the size of the heap) and of
+//! individual threads (by limiting the net number of bytes allocated through the thread).
+
+#[cfg(feature = "tls")]
+use core::cell::Cell;
+use core::sync::atomic::{self, AtomicUsize};
+
+use fail::AllocError;
+use stats;
+
+#[cfg(feature = "tls")]
+use tls;
+
+/// The maximal size of the heap (in bytes).
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(!0);
+
+#[cfg(feature = "tls")]
+tls! {
+    /// The net number of bytes allocated through the current thread.
+    static THREAD_USAGE: Cell<usize> = Cell::new(0);
+    /// The maximal net number of bytes allocated through the current thread.
+    static THREAD_LIMIT: Cell<usize> = Cell::new(!0);
+}
+
+/// Set the maximal size of the heap.
+///
+/// Requests which would grow the heap beyond this limit fail with `AllocError::LimitExceeded`
+/// (which goes through the OOM handlers for the infallible functions). Memory already acquired is
+/// not affected.
+#[inline]
+pub fn set_heap_limit(bytes: usize) {
+    // Logging...
+    log!(NOTE, "Setting the heap limit to {} bytes.", bytes);
+
+    HEAP_LIMIT.store(bytes, atomic::Ordering::SeqCst);
+}
+
+/// Get the maximal size of the heap.
+///
+/// This is `!0` if no limit is set.
+#[inline]
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(atomic::Ordering::SeqCst)
+}
+
+/// Check if the heap can grow by `size` bytes.
+///
+/// This is called by the memory sources before acquiring memory from the OS.
+#[inline]
+pub fn check_heap(size: usize) -> Result<(), AllocError> {
+    match stats::stats().heap_size.checked_add(size) {
+        Some(new) if new <= heap_limit() => Ok(()),
+        _ => {
+            log!(WARNING, "Growing the heap by {} bytes would exceed the heap limit.", size);
+
+            Err(AllocError::LimitExceeded)
+        },
+    }
+}
+
+/// Set the maximal net number of bytes allocated through the current thread.
+///
+/// Requests which would take the net allocated bytes of this thread beyond the limit fail with
+/// `AllocError::LimitExceeded` (which goes through the OOM handlers for the infallible
+/// functions).
+///
+/// Note that buffers are accounted to the thread freeing them, thus buffers passed between threads
+/// move their usage along.
+#[inline]
+#[cfg(feature = "tls")]
+pub fn set_thread_limit(bytes: usize) {
+    // Logging...
+    log!(NOTE, "Setting the thread limit to {} bytes.", bytes);
+
+    THREAD_LIMIT.with(|x| x.set(bytes));
+}
+
+/// Get the maximal net number of bytes allocated through the current thread.
+///
+/// This is `!0` if no limit is set.
+#[inline]
+#[cfg(feature = "tls")]
+pub fn thread_limit() -> usize {
+    THREAD_LIMIT.with(|x| x.get())
+}
+
+/// Get the net number of bytes allocated through the current thread.
+#[inline]
+#[cfg(feature = "tls")]
+pub fn thread_usage() -> usize {
+    THREAD_USAGE.with(|x| x.get())
+}
+
+/// Charge `size` bytes to the current thread.
+///
+/// If this would exceed the thread limit, nothing is charged and an error is returned.
+#[inline]
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+pub fn charge(size: usize) -> Result<(), AllocError> {
+    #[cfg(feature = "tls")]
+    {
+        THREAD_USAGE.with(|usage| {
+            match usage.get().checked_add(size) {
+                Some(new) if new <= thread_limit() => {
+                    usage.set(new);
+
+                    Ok(())
+                },
+                _ => {
+                    log!(WARNING, "Allocating {} bytes would exceed the thread limit.", size);
+
+                    Err(AllocError::LimitExceeded)
+                },
+            }
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    {
+        Ok(())
+    }
+}
+
+/// Refund `size` bytes to the current thread.
+#[inline]
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+pub fn refund(size: usize) {
+    #[cfg(feature = "tls")]
+    THREAD_USAGE.with(|usage| usage.set(usage.get().saturating_sub(size)));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "tls")]
+    fn test_thread_limit() {
+        let usage = thread_usage();
+        set_thread_limit(usage + 100);
+
+        assert!(charge(60).is_ok());
+        assert_eq!(charge(60), Err(AllocError::LimitExceeded));
+        refund(60);
+        assert!(charge(100).is_ok());
+        assert_eq!(thread_usage(), usage + 100);
+
+        refund(100);
+        set_thread_limit(!0);
+    }
+}
@@ -143,7 +143,8 @@ pub mod internal {
     use core::cell::Cell;
     use core::ops::Range;
 
-    use shim::config;
+    use config::{self, Param};
+    use shim;
 
     use sync;
 
@@ -153,6 +154,24 @@ pub mod internal {
     #[cfg(not(feature = "no_log_lock"))]
     pub static LOG_LOCK: Mutex<()> = Mutex::new(());
 
+    /// Acquire the log lock before forking.
+    ///
+    /// The lock is kept until `fork_unlock` is called.
+    #[cfg(not(feature = "no_log_lock"))]
+    pub fn fork_lock() {
+        ::core::mem::forget(LOG_LOCK.lock());
+    }
+
+    /// Release the log lock after forking.
+    ///
+    /// # Safety
+    ///
+    /// This must be paired with a preceding call to `fork_lock`.
+    #[cfg(not(feature = "no_log_lock"))]
+    pub unsafe fn fork_unlock() {
+        LOG_LOCK.force_unlock();
+    }
+
     /// A log writer.
     ///
     /// This writes to the shim logger.
@@ -179,7 +198,7 @@ pub mod internal {
 
     impl fmt::Write for LogWriter {
         fn write_str(&mut self, s: &str) -> fmt::Result {
-            if config::log(s) == !0 { Err(fmt::Error) } else { Ok(()) }
+            if shim::config::log(s) == !0 { Err(fmt::Error) } else { Ok(()) }
         }
     }
 
@@ -341,9 +360,8 @@ pub mod internal {
     }
 
     /// Check if this log level is enabled.
-    #[allow(absurd_extreme_comparisons)]
     #[inline]
     pub fn level(lv: u8) -> bool {
-        lv >= config::MIN_LOG_LEVEL
+        lv as usize >= config::get(Param::LogLevel)
     }
 }
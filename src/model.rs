@@ -0,0 +1,449 @@
+//! Model-based testing of the bookkeeper.
+//!
+//! This runs random sequences of operations against a bookkeeper backed by a simulated memory
+//! source (see `sim`), and compares it against a reference model after every operation. The model
+//! keeps track of the buffers handed out and their content, and thus knows which bytes should be
+//! free: Every byte acquired from the source must be either free, allocated, or part of the block
+//! pool (exactly one of them).
+//!
+//! Failing sequences are shrunk to a minimal reproduction, which is printed as a regression test,
+//! to be added to `test_regressions`. To replay a single sequence, set `RALLOC_MODEL_SEED`.
+
+use core::cell::Cell;
+use core::{cmp, mem};
+use std::{io, panic};
+use std::io::Write;
+use std::vec::Vec;
+
+use block::Block;
+use bookkeeper::Allocator;
+use fail::AllocError;
+use ptr::Pointer;
+use random;
+use sim::{Source, SimAllocator};
+
+/// The size of the simulated memory.
+const MEMORY: usize = 65536;
+/// The maximal number of buffers alive at once.
+const MAX_LIVE: usize = 32;
+/// The number of operations in a random sequence.
+const OPS: usize = 64;
+/// The number of random sequences tested.
+const SEQUENCES: u64 = 256;
+
+/// An operation on the allocator.
+///
+/// Buffers are referred to by their index among the live buffers (modulo the number of them),
+/// such that removing operations from a sequence keeps it valid.
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    /// Allocate a buffer.
+    Alloc { size: usize, align: usize },
+    /// Free a buffer.
+    Free { id: usize },
+    /// Free the tail of a buffer.
+    PartialFree { id: usize, size: usize },
+    /// Reallocate a buffer.
+    Realloc { id: usize, size: usize, align: usize },
+    /// Reallocate a buffer inplace.
+    ReallocInplace { id: usize, size: usize },
+}
+
+/// A buffer handed out by the allocator.
+#[derive(Clone, Copy)]
+struct Buffer {
+    /// The address of the buffer.
+    ptr: usize,
+    /// The size of the buffer.
+    size: usize,
+    /// The tag of the content pattern.
+    tag: u8,
+}
+
+impl Buffer {
+    /// Create a buffer from a block handed out.
+    fn new(block: Block, tag: u8) -> Buffer {
+        Buffer {
+            size: block.size(),
+            ptr: *Pointer::from(block) as usize,
+            tag: tag,
+        }
+    }
+
+    /// Get the block of this buffer.
+    fn block(&self) -> Block {
+        unsafe {
+            // The buffer is handed out by the allocator.
+            Block::from_raw_parts(Pointer::new(self.ptr as *mut u8), self.size)
+        }
+    }
+
+    /// Fill the buffer with its pattern.
+    fn fill(&self) {
+        for i in 0..self.size {
+            unsafe {
+                // The buffer is handed out by the allocator.
+                *((self.ptr + i) as *mut u8) = self.tag.wrapping_add(i as u8);
+            }
+        }
+    }
+
+    /// Check that the first `size` bytes hold the pattern.
+    fn verify(&self, size: usize) -> bool {
+        (0..size).all(|i| unsafe {
+            // The buffer is handed out by the allocator.
+            *((self.ptr + i) as *const u8) == self.tag.wrapping_add(i as u8)
+        })
+    }
+}
+
+/// Get the address of a block.
+fn addr(block: &Block) -> usize {
+    *Pointer::from(block.empty_left()) as usize
+}
+
+/// Compare the allocator against the model.
+///
+/// Every byte acquired from the source must be either free, allocated, or part of the block pool.
+fn compare(alloc: &SimAllocator, start: usize, live: &[Buffer]) -> Result<(), &'static str> {
+    let used = alloc.source.used();
+    let mut owned = [false; MEMORY];
+
+    {
+        let mut mark = |ptr: usize, size: usize| {
+            if ptr < start || ptr + size > start + used {
+                return Err("Block outside of the acquired memory.");
+            }
+
+            for x in &mut owned[ptr - start..ptr - start + size] {
+                if *x {
+                    return Err("Overlapping blocks.");
+                }
+
+                *x = true;
+            }
+
+            Ok(())
+        };
+
+        for block in alloc.blocks() {
+            mark(addr(block), block.size())?;
+        }
+        for buf in live {
+            mark(buf.ptr, buf.size)?;
+        }
+        mark(alloc.blocks().as_ptr() as usize, alloc.capacity() * mem::size_of::<Block>())?;
+    }
+
+    if owned[..used].iter().any(|&x| !x) {
+        return Err("Leaked memory.");
+    }
+
+    Ok(())
+}
+
+/// Run a sequence of operations.
+///
+/// On failure, the index of the failing operation and a description is returned.
+fn run(ops: &[Op]) -> Result<(), (usize, &'static str)> {
+    let mut memory = [0u64; MEMORY / 8];
+    let mut alloc = SimAllocator::new(unsafe {
+        Source::new(memory.as_mut_ptr() as *mut u8, MEMORY)
+    });
+    let start = memory.as_ptr() as usize;
+
+    let mut live = [Buffer { ptr: 0, size: 0, tag: 0 }; MAX_LIVE];
+    let mut len = 0;
+    let mut tag = 0u8;
+
+    for (step, &op) in ops.iter().enumerate() {
+        match op {
+            Op::Alloc { size, align } => {
+                if len == MAX_LIVE { continue; }
+
+                match alloc.try_alloc(size, align) {
+                    Ok(block) => {
+                        if !block.aligned_to(align) {
+                            return Err((step, "Misaligned buffer."));
+                        }
+
+                        tag = tag.wrapping_add(1);
+                        let buf = Buffer::new(block, tag);
+                        if buf.size != size {
+                            return Err((step, "Buffer of wrong size."));
+                        }
+
+                        buf.fill();
+                        live[len] = buf;
+                        len += 1;
+                    },
+                    Err(AllocError::OutOfMemory) => (),
+                    Err(_) => return Err((step, "Unexpected error.")),
+                }
+            },
+            Op::Free { id } => {
+                if len == 0 { continue; }
+                let ind = id % len;
+
+                if !live[ind].verify(live[ind].size) {
+                    return Err((step, "Buffer corrupted."));
+                }
+
+                alloc.free(live[ind].block());
+                len -= 1;
+                live[ind] = live[len];
+            },
+            Op::PartialFree { id, size } => {
+                if len == 0 { continue; }
+                let ind = id % len;
+
+                // Keep at least one byte.
+                let keep = live[ind].size - size % live[ind].size;
+                let (_, tail) = live[ind].block().split(keep);
+                alloc.free(tail);
+                live[ind].size = keep;
+            },
+            Op::Realloc { id, size, align } => {
+                if len == 0 { continue; }
+                let ind = id % len;
+                let old = live[ind];
+
+                match alloc.try_realloc(old.block(), size, align) {
+                    Ok(block) => {
+                        if !block.aligned_to(align) {
+                            return Err((step, "Misaligned buffer."));
+                        }
+
+                        let buf = Buffer::new(block, old.tag);
+                        if !buf.verify(cmp::min(old.size, size)) {
+                            return Err((step, "Content lost in reallocation."));
+                        }
+
+                        buf.fill();
+                        live[ind] = buf;
+                    },
+                    Err(AllocError::OutOfMemory) => if !old.verify(old.size) {
+                        return Err((step, "Content lost in failed reallocation."));
+                    },
+                    Err(_) => return Err((step, "Unexpected error.")),
+                }
+            },
+            Op::ReallocInplace { id, size } => {
+                if len == 0 { continue; }
+                let ind = id % len;
+                let old = live[ind];
+
+                match alloc.realloc_inplace(old.block(), size) {
+                    Ok(block) => {
+                        let buf = Buffer::new(block, old.tag);
+                        if buf.ptr != old.ptr {
+                            return Err((step, "Inplace reallocation moved the buffer."));
+                        }
+                        if !buf.verify(cmp::min(old.size, size)) {
+                            return Err((step, "Content lost in inplace reallocation."));
+                        }
+
+                        buf.fill();
+                        live[ind] = buf;
+                    },
+                    Err(_) => if !old.verify(old.size) {
+                        return Err((step, "Content lost in failed inplace reallocation."));
+                    },
+                }
+            },
+        }
+
+        // The bookkeeper must be consistent, and agree with the model.
+        alloc.check();
+        compare(&alloc, start, &live[..len]).map_err(|msg| (step, msg))?;
+    }
+
+    Ok(())
+}
+
+/// Does a sequence fail (including panics)?
+fn fails(ops: &[Op]) -> bool {
+    match panic::catch_unwind(panic::AssertUnwindSafe(|| run(ops))) {
+        Ok(Ok(())) => false,
+        _ => true,
+    }
+}
+
+/// Run a sequence, and panic with a description, if it fails.
+fn check(ops: &[Op]) {
+    if let Err((step, msg)) = run(ops) {
+        panic!("Operation {} ({:?}) failed: {}", step, ops[step], msg);
+    }
+}
+
+/// Generate a random sequence of operations.
+fn generate(seed: u64) -> Vec<Op> {
+    // Mix the seed, such that neighboring seeds give unrelated (and distinct) states. The state
+    // must be non-zero.
+    let state = Cell::new(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1);
+    let next = |n: usize| (random::step(&state) % n as u64) as usize;
+
+    (0..OPS).map(|_| {
+        // Mostly small sizes, occasionally large ones (which might run out of memory).
+        let size = if next(8) == 0 { 1 + next(8192) } else { 1 + next(256) };
+        let align = 1 << next(7);
+        let id = next(MAX_LIVE);
+
+        match next(6) {
+            0 | 1 => Op::Alloc { size: size, align: align },
+            2 => Op::Free { id: id },
+            3 => Op::PartialFree { id: id, size: size },
+            4 => Op::Realloc { id: id, size: size, align: align },
+            _ => Op::ReallocInplace { id: id, size: size },
+        }
+    }).collect()
+}
+
+/// Get simpler variants of an operation.
+fn simplify(op: Op) -> [Option<Op>; 3] {
+    match op {
+        Op::Alloc { size, align } => [
+            if size > 1 { Some(Op::Alloc { size: size / 2, align: align }) } else { None },
+            if align > 1 { Some(Op::Alloc { size: size, align: 1 }) } else { None },
+            None,
+        ],
+        Op::Free { id } => [
+            if id > 0 { Some(Op::Free { id: 0 }) } else { None },
+            None,
+            None,
+        ],
+        Op::PartialFree { id, size } => [
+            if size > 1 { Some(Op::PartialFree { id: id, size: size / 2 }) } else { None },
+            if id > 0 { Some(Op::PartialFree { id: 0, size: size }) } else { None },
+            None,
+        ],
+        Op::Realloc { id, size, align } => [
+            if size > 1 { Some(Op::Realloc { id: id, size: size / 2, align: align }) } else { None },
+            if align > 1 { Some(Op::Realloc { id: id, size: size, align: 1 }) } else { None },
+            if id > 0 { Some(Op::Realloc { id: 0, size: size, align: align }) } else { None },
+        ],
+        Op::ReallocInplace { id, size } => [
+            if size > 1 { Some(Op::ReallocInplace { id: id, size: size / 2 }) } else { None },
+            if id > 0 { Some(Op::ReallocInplace { id: 0, size: size }) } else { None },
+            None,
+        ],
+    }
+}
+
+/// Shrink a failing sequence to a (locally) minimal one.
+fn shrink(mut ops: Vec<Op>) -> Vec<Op> {
+    loop {
+        let mut progress = false;
+
+        // Remove chunks of operations, from large to small.
+        let mut chunk = ops.len() / 2;
+        while chunk > 0 {
+            let mut i = 0;
+            while i + chunk <= ops.len() {
+                let mut candidate = ops.clone();
+                candidate.drain(i..i + chunk);
+
+                if fails(&candidate) {
+                    ops = candidate;
+                    progress = true;
+                } else {
+                    i += chunk;
+                }
+            }
+
+            chunk /= 2;
+        }
+
+        // Simplify the remaining operations.
+        for i in 0..ops.len() {
+            for &simpler in simplify(ops[i]).iter().filter_map(|x| x.as_ref()) {
+                let mut candidate = ops.clone();
+                candidate[i] = simpler;
+
+                if fails(&candidate) {
+                    ops = candidate;
+                    progress = true;
+                    break;
+                }
+            }
+        }
+
+        if !progress {
+            return ops;
+        }
+    }
+}
+
+/// Print a sequence as a regression test.
+fn export(seed: u64, ops: &[Op]) {
+    let stderr = io::stderr();
+    let mut out = stderr.lock();
+
+    let _ = writeln!(out, "Minimal failing sequence (seed {}):\n", seed);
+    let _ = writeln!(out, "    // Seed {}.", seed);
+    let _ = writeln!(out, "    check(&[");
+    for op in ops {
+        let _ = writeln!(out, "        Op::{:?},", op);
+    }
+    let _ = writeln!(out, "    ]);");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn test_random() {
+        let seeds = match env::var("RALLOC_MODEL_SEED") {
+            Ok(seed) => {
+                let seed = seed.parse().expect("Invalid RALLOC_MODEL_SEED.");
+                seed..seed + 1
+            },
+            Err(_) => 0..SEQUENCES,
+        };
+
+        for seed in seeds {
+            let ops = generate(seed);
+
+            if fails(&ops) {
+                let ops = shrink(ops);
+                export(seed, &ops);
+
+                check(&ops);
+                panic!("Sequence {} fails nondeterministically.", seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_regressions() {
+        // Partial frees and inplace reallocation next to the program break.
+        check(&[
+            Op::Alloc { size: 100, align: 1 },
+            Op::PartialFree { id: 0, size: 50 },
+            Op::ReallocInplace { id: 0, size: 200 },
+            Op::Free { id: 0 },
+        ]);
+
+        // Moving an aligned buffer around.
+        check(&[
+            Op::Alloc { size: 16, align: 64 },
+            Op::Realloc { id: 0, size: 4096, align: 64 },
+            Op::Alloc { size: 8, align: 1 },
+            Op::ReallocInplace { id: 0, size: 8 },
+            Op::Realloc { id: 0, size: 8192, align: 8 },
+            Op::Free { id: 1 },
+            Op::Free { id: 0 },
+        ]);
+
+        // Running out of memory.
+        check(&[
+            Op::Alloc { size: 32, align: 8 },
+            Op::Alloc { size: 1 << 20, align: 8 },
+            Op::Realloc { id: 0, size: 1 << 20, align: 8 },
+            Op::Free { id: 0 },
+        ]);
+    }
+}
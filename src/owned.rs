@@ -0,0 +1,263 @@
+//! Tracking of owned memory.
+//!
+//! When compiled with `validate`, ralloc keeps track of the address ranges acquired from the OS.
+//! This allows it to reject frees and reallocations of buffers it never handed out (e.g. stack
+//! buffers or buffers from another allocator).
+
+use prelude::*;
+
+use core::mem;
+
+use fail::Corruption;
+
+/// The maximal number of disjoint segments, which can be tracked.
+///
+/// Segments acquired through BRK are usually adjacent, and thus merged into one, so this should
+/// rarely be exceeded.
+const MAX_SEGMENTS: usize = 64;
+
+/// The owned segments.
+static SEGMENTS: Mutex<Segments> = Mutex::new(Segments::new());
+
+/// A table of owned address ranges.
+struct Segments {
+    /// The segments.
+    ///
+    /// The segments are represented by the start and end address, and are sorted and
+    /// non-adjacent.
+    segments: [(usize, usize); MAX_SEGMENTS],
+    /// The number of segments in use.
+    len: usize,
+    /// Has the table overflowed?
+    ///
+    /// If so, some owned memory is not tracked, and validation is disabled to avoid false
+    /// positives.
+    overflowed: bool,
+}
+
+impl Segments {
+    /// Create a new, empty table.
+    const fn new() -> Segments {
+        Segments {
+            segments: [(0, 0); MAX_SEGMENTS],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Find the index of the first segment ending after `addr`.
+    fn find(&self, addr: usize) -> usize {
+        self.segments[..self.len].iter().take_while(|&&(_, end)| end <= addr).count()
+    }
+
+    /// Insert a segment into the table.
+    ///
+    /// Adjacent segments are merged.
+    fn insert(&mut self, start: usize, end: usize) {
+        // Short circuit on empty segments.
+        if start == end { return; }
+
+        // Find the first segment ending at or after `start`.
+        let ind = self.segments[..self.len].iter().take_while(|&&(_, e)| e < start).count();
+
+        // Merge to the left.
+        if ind < self.len && self.segments[ind].1 == start {
+            self.segments[ind].1 = end;
+
+            // Merge the gap to the right, if closed.
+            if ind + 1 < self.len && self.segments[ind + 1].0 == end {
+                self.segments[ind].1 = self.segments[ind + 1].1;
+                self.remove_at(ind + 1);
+            }
+        // Merge to the right.
+        } else if ind < self.len && self.segments[ind].0 == end {
+            self.segments[ind].0 = start;
+        } else if self.len == MAX_SEGMENTS {
+            log!(WARNING, "Too many owned segments. Disabling validation.");
+
+            self.overflowed = true;
+        } else {
+            // Shift the segments to the right and insert.
+            for i in (ind..self.len).rev() {
+                self.segments[i + 1] = self.segments[i];
+            }
+            self.segments[ind] = (start, end);
+            self.len += 1;
+        }
+    }
+
+    /// Remove a segment from the table.
+    ///
+    /// The segment is assumed to be contained in one owned segment.
+    fn remove(&mut self, start: usize, end: usize) {
+        // Short circuit on empty segments.
+        if start == end { return; }
+
+        let ind = self.find(start);
+        if ind == self.len || self.segments[ind].0 > start || self.segments[ind].1 < end {
+            log!(WARNING, "Releasing untracked segment 0x{:x}..0x{:x}.", start, end);
+            return;
+        }
+
+        let (seg_start, seg_end) = self.segments[ind];
+        if seg_start == start && seg_end == end {
+            // Remove it entirely.
+            self.remove_at(ind);
+        } else if seg_start == start {
+            // Shrink from the left.
+            self.segments[ind].0 = end;
+        } else if seg_end == end {
+            // Shrink from the right.
+            self.segments[ind].1 = start;
+        } else {
+            // Split the segment into two.
+            self.segments[ind].1 = start;
+            self.insert(end, seg_end);
+        }
+    }
+
+    /// Remove the segment at some index.
+    fn remove_at(&mut self, ind: usize) {
+        for i in ind..self.len - 1 {
+            self.segments[i] = self.segments[i + 1];
+        }
+        self.len -= 1;
+    }
+
+    /// Check that the range `start..end` lies inside a single owned segment.
+    fn check(&self, start: usize, end: usize) -> Result<(), Corruption> {
+        // Empty or untrackable ranges are always valid.
+        if start == end || self.overflowed { return Ok(()); }
+
+        let ind = self.find(start);
+        if ind == self.len || self.segments[ind].0 > start {
+            Err(Corruption::ForeignFree {
+                ptr: start as *const u8,
+                size: end - start,
+            })
+        } else if self.segments[ind].1 < end {
+            Err(Corruption::StraddlingFree {
+                ptr: start as *const u8,
+                size: end - start,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Mark a segment as owned by ralloc.
+pub fn acquire(ptr: *const u8, size: usize) {
+    log!(INTERNAL, "Acquiring segment 0x{:x}[{}].", ptr as usize, size);
+
+    SEGMENTS.lock().insert(ptr as usize, ptr as usize + size);
+}
+
+/// Mark a segment as no longer owned by ralloc.
+pub fn release(ptr: *const u8, size: usize) {
+    log!(INTERNAL, "Releasing segment 0x{:x}[{}].", ptr as usize, size);
+
+    SEGMENTS.lock().remove(ptr as usize, ptr as usize + size);
+}
+
+/// Validate that a buffer lies in the memory owned by ralloc.
+///
+/// If it is not contained in a single owned segment, the corruption is returned.
+pub fn validate(ptr: *const u8, size: usize) -> Result<(), Corruption> {
+    SEGMENTS.lock().check(ptr as usize, ptr as usize + size)
+}
+
+/// Acquire the segment table lock before forking.
+///
+/// The lock is kept until `fork_unlock` is called.
+pub fn fork_lock() {
+    mem::forget(SEGMENTS.lock());
+}
+
+/// Release the segment table lock after forking.
+///
+/// # Safety
+///
+/// This must be paired with a preceding call to `fork_lock`.
+pub unsafe fn fork_unlock() {
+    SEGMENTS.force_unlock();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut seg = Segments::new();
+
+        seg.insert(100, 200);
+        seg.insert(300, 400);
+        assert_eq!(seg.len, 2);
+
+        seg.insert(200, 300);
+        assert_eq!(seg.len, 1);
+        assert!(seg.check(100, 400).is_ok());
+
+        seg.insert(50, 100);
+        seg.insert(400, 450);
+        assert_eq!(seg.len, 1);
+        assert!(seg.check(50, 450).is_ok());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut seg = Segments::new();
+
+        seg.insert(100, 200);
+        seg.insert(300, 400);
+
+        assert!(seg.check(100, 200).is_ok());
+        assert!(seg.check(150, 160).is_ok());
+        assert!(seg.check(10, 10).is_ok());
+        match seg.check(10, 20) {
+            Err(Corruption::ForeignFree { .. }) => {},
+            _ => panic!("Foreign free not detected."),
+        }
+        match seg.check(250, 260) {
+            Err(Corruption::ForeignFree { .. }) => {},
+            _ => panic!("Foreign free not detected."),
+        }
+        match seg.check(150, 350) {
+            Err(Corruption::StraddlingFree { .. }) => {},
+            _ => panic!("Straddling free not detected."),
+        }
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut seg = Segments::new();
+
+        seg.insert(100, 400);
+        seg.remove(300, 400);
+        assert!(seg.check(300, 310).is_err());
+        assert!(seg.check(100, 300).is_ok());
+
+        seg.remove(150, 200);
+        assert_eq!(seg.len, 2);
+        assert!(seg.check(150, 160).is_err());
+        assert!(seg.check(100, 150).is_ok());
+        assert!(seg.check(200, 300).is_ok());
+
+        seg.remove(100, 150);
+        seg.remove(200, 300);
+        assert_eq!(seg.len, 0);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut seg = Segments::new();
+
+        for i in 0..MAX_SEGMENTS + 1 {
+            seg.insert(i * 100, i * 100 + 50);
+        }
+
+        assert!(seg.overflowed);
+        assert!(seg.check(60, 70).is_ok());
+    }
+}
@@ -0,0 +1,151 @@
+//! The local memtrim policy.
+//!
+//! Local allocators hand their free memory back to the global allocator, when they hold too much
+//! of it. A fixed limit makes threads with large, steady working sets bounce memory between the
+//! local and the global allocator, so the limit adapts to the recent allocation volume of the
+//! thread instead. Frees advance the sampling too, such that the limit decays, when the thread
+//! stops allocating.
+
+use prelude::*;
+
+use core::cmp;
+
+use config::{self, Param};
+use shim::config::HOT_BLOCKS;
+
+/// A local memtrim policy.
+#[derive(Clone)]
+pub struct TrimPolicy {
+    /// The smoothed number of bytes allocated per window.
+    volume: usize,
+    /// The number of bytes allocated in the current window.
+    window_bytes: usize,
+    /// The number of operations (allocations and frees) in the current window.
+    window_ops: usize,
+    /// The addresses of recently reused blocks.
+    ///
+    /// This is a ring buffer, where the oldest address is overwritten.
+    hot: [usize; HOT_BLOCKS],
+    /// The index of the next address in `hot`.
+    next: usize,
+}
+
+impl TrimPolicy {
+    /// Create a new policy.
+    pub fn new() -> TrimPolicy {
+        TrimPolicy {
+            volume: 0,
+            window_bytes: 0,
+            window_ops: 0,
+            hot: [0; HOT_BLOCKS],
+            next: 0,
+        }
+    }
+
+    /// Register an allocation.
+    ///
+    /// `reused` tells if the block was served from the pool.
+    pub fn on_alloc(&mut self, block: &Block, reused: bool) {
+        self.window_bytes = self.window_bytes.saturating_add(block.size());
+        self.tick();
+
+        if reused {
+            self.hot[self.next] = *Pointer::from(block.empty_left()) as usize;
+            self.next = (self.next + 1) % HOT_BLOCKS;
+        }
+    }
+
+    /// Register a free.
+    ///
+    /// Frees allocate nothing, so windows of frees only decay the volume.
+    pub fn on_free(&mut self) {
+        self.tick();
+    }
+
+    /// Count an operation, and complete the window if it is over.
+    fn tick(&mut self) {
+        self.window_ops += 1;
+
+        if self.window_ops >= config::get(Param::LocalMemtrimWindow) {
+            // The window is over. The volume is an exponential moving average, such that a single
+            // burst won't move the limit much.
+            self.volume = self.volume - self.volume / 4 + self.window_bytes / 4;
+            self.window_bytes = 0;
+            self.window_ops = 0;
+        }
+    }
+
+    /// Get the memtrim limit.
+    ///
+    /// When the pool holds more bytes than this, it is memtrimmed. This is the smoothed volume
+    /// (bounded by `Param::LocalMemtrimMax`), but at least `Param::LocalMemtrimLimit`.
+    pub fn limit(&self) -> usize {
+        cmp::max(config::get(Param::LocalMemtrimLimit),
+                 cmp::min(self.volume, config::get(Param::LocalMemtrimMax)))
+    }
+
+    /// Get the memtrim chock.
+    ///
+    /// The memtrimming continues until the pool holds less bytes than this. When the limit is
+    /// raised by the volume, this is half of the limit, such that the pool must grow considerably
+    /// before it is memtrimmed again (hysteresis).
+    pub fn stop(&self) -> usize {
+        let limit = self.limit();
+        let stop = config::get(Param::LocalMemtrimStop);
+
+        if limit > config::get(Param::LocalMemtrimLimit) {
+            cmp::max(limit / 2, stop)
+        } else {
+            stop
+        }
+    }
+
+    /// Was some part of this free block reused recently?
+    pub fn is_hot(&self, block: &Block) -> bool {
+        let start = *Pointer::from(block.empty_left()) as usize;
+        let end = start + block.size();
+
+        self.hot.iter().any(|&x| x >= start && x < end)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use prelude::*;
+
+    use config::{self, Param};
+
+    #[test]
+    fn test_adapt() {
+        let mut policy = TrimPolicy::new();
+        let arr = [0u8; 64];
+        let block = unsafe { Block::from_raw_parts(Pointer::new(arr.as_ptr() as *mut u8), 64) };
+
+        assert_eq!(policy.limit(), config::get(Param::LocalMemtrimLimit));
+        assert_eq!(policy.stop(), config::get(Param::LocalMemtrimStop));
+        assert!(!policy.is_hot(&block));
+
+        // Allocate a lot.
+        let huge = unsafe {
+            Block::from_raw_parts(Pointer::new(arr.as_ptr() as *mut u8), 1 << 20)
+        };
+        for _ in 0..4 * config::get(Param::LocalMemtrimWindow) {
+            policy.on_alloc(&huge, false);
+        }
+
+        assert!(policy.limit() > config::get(Param::LocalMemtrimLimit));
+        assert_eq!(policy.stop(), policy.limit() / 2);
+
+        policy.on_alloc(&block, true);
+        assert!(policy.is_hot(&block));
+
+        // Stop allocating. The limit decays.
+        for _ in 0..64 * config::get(Param::LocalMemtrimWindow) {
+            policy.on_free();
+        }
+
+        assert_eq!(policy.limit(), config::get(Param::LocalMemtrimLimit));
+    }
+}
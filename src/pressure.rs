@@ -0,0 +1,290 @@
+//! Memory pressure notifications.
+//!
+//! This allows the application to register callbacks, which are called when memory is getting
+//! tight, such that e.g. caches can shrink themselves. The registry is fixed-capacity, and never
+//! allocates.
+//!
+//! Events are raised inside the allocator, but the callbacks are only called when no allocator
+//! lock is held, allowing them to free memory.
+
+use prelude::*;
+
+use core::mem;
+use core::sync::atomic::{self, AtomicBool, AtomicUsize};
+
+use stats;
+
+/// The maximal number of registered callbacks.
+const MAX_CALLBACKS: usize = 8;
+/// The maximal number of high-water marks.
+const MAX_MARKS: usize = 8;
+
+/// The registered callbacks.
+static CALLBACKS: Mutex<[Option<fn(Pressure)>; MAX_CALLBACKS]> = Mutex::new([None; MAX_CALLBACKS]);
+/// The high-water marks.
+///
+/// Unused entries are zero.
+static MARKS: Mutex<[usize; MAX_MARKS]> = Mutex::new([0; MAX_MARKS]);
+
+/// Is any event pending?
+static PENDING: AtomicBool = AtomicBool::new(false);
+/// The pending high-water mark crossed (zero if none).
+static PENDING_MARK: AtomicUsize = AtomicUsize::new(0);
+/// The size of the pending failed request plus one (zero if none).
+static PENDING_FAILURE: AtomicUsize = AtomicUsize::new(0);
+/// The number of failures of the memory source.
+///
+/// This tells the requests, whether they failed due to the source, regardless of which thread
+/// dispatches the event.
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+/// The heap size, when the high-water marks were last checked.
+static LAST_HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Is the memory source failing?
+///
+/// This is set when the source first fails, and cleared when it succeeds again. It makes sure
+/// that the callbacks are only notified once per failure.
+static SOURCE_FAILING: AtomicBool = AtomicBool::new(false);
+
+/// A memory pressure event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pressure {
+    /// The heap grew beyond a high-water mark.
+    HighWater {
+        /// The crossed mark.
+        mark: usize,
+        /// The size of the heap.
+        heap_size: usize,
+    },
+    /// The memory source failed to provide memory.
+    ///
+    /// This is not raised when the heap limit (see `set_heap_limit`) is exceeded. After the
+    /// callbacks have been called, the request is retried, before going through the OOM handlers.
+    SourceFailed {
+        /// The size of the request.
+        size: usize,
+    },
+}
+
+/// Register a memory pressure callback.
+///
+/// The callback is called on memory pressure events. It is allowed to allocate and free.
+///
+/// # Errors
+///
+/// If the registry is full, an error is returned.
+pub fn add_pressure_callback(callback: fn(Pressure)) -> Result<(), ()> {
+    // Logging...
+    log!(NOTE, "Registering a memory pressure callback.");
+
+    for i in CALLBACKS.lock().iter_mut() {
+        if i.is_none() {
+            *i = Some(callback);
+
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+/// Unregister a memory pressure callback.
+///
+/// # Errors
+///
+/// If the callback is not registered, an error is returned.
+pub fn remove_pressure_callback(callback: fn(Pressure)) -> Result<(), ()> {
+    // Logging...
+    log!(NOTE, "Unregistering a memory pressure callback.");
+
+    for i in CALLBACKS.lock().iter_mut() {
+        if *i == Some(callback) {
+            *i = None;
+
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+/// Add a high-water mark.
+///
+/// When the heap grows beyond `bytes`, the callbacks are notified. They will be notified again,
+/// if the heap shrinks below the mark and then grows beyond it again.
+///
+/// # Errors
+///
+/// If the maximal number of marks is reached, or `bytes` is zero, an error is returned.
+pub fn add_high_water_mark(bytes: usize) -> Result<(), ()> {
+    // Logging...
+    log!(NOTE, "Adding a high-water mark at {} bytes.", bytes);
+
+    if bytes == 0 {
+        return Err(());
+    }
+
+    for i in MARKS.lock().iter_mut() {
+        if *i == 0 {
+            *i = bytes;
+
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+/// Remove all the high-water marks.
+pub fn clear_high_water_marks() {
+    // Logging...
+    log!(NOTE, "Clearing the high-water marks.");
+
+    *MARKS.lock() = [0; MAX_MARKS];
+}
+
+/// Check if the heap crossed a high-water mark since the last check.
+///
+/// If so, an event is raised.
+pub fn check_heap() {
+    let heap_size = stats::stats().heap_size;
+    let last = LAST_HEAP_SIZE.swap(heap_size, atomic::Ordering::SeqCst);
+
+    // Short-circuit, if the heap did not grow.
+    if heap_size <= last {
+        return;
+    }
+
+    // Find the highest mark crossed.
+    let mark = MARKS.lock().iter()
+        .cloned()
+        .filter(|&mark| last < mark && mark <= heap_size)
+        .max();
+
+    if let Some(mark) = mark {
+        log!(NOTE, "The heap crossed the high-water mark at {} bytes.", mark);
+
+        PENDING_MARK.store(mark, atomic::Ordering::SeqCst);
+        PENDING.store(true, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Register that the memory source failed to provide `size` bytes.
+///
+/// An event is raised the first time the source fails. It is not raised again until the source
+/// succeeded.
+pub fn source_failed(size: usize) {
+    FAILURES.fetch_add(1, atomic::Ordering::SeqCst);
+
+    if !SOURCE_FAILING.swap(true, atomic::Ordering::SeqCst) {
+        log!(WARNING, "The memory source failed. Notifying the pressure callbacks.");
+
+        PENDING_FAILURE.store(size.saturating_add(1), atomic::Ordering::SeqCst);
+        PENDING.store(true, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Get the number of failures of the memory source.
+///
+/// This is read before a request, and passed to [`dispatch`](./fn.dispatch.html) afterwards.
+#[inline]
+pub fn failures() -> usize {
+    FAILURES.load(atomic::Ordering::SeqCst)
+}
+
+/// Register that the memory source succeeded.
+#[inline]
+pub fn source_succeeded() {
+    SOURCE_FAILING.store(false, atomic::Ordering::Relaxed);
+}
+
+/// Call the callbacks on the pending events.
+///
+/// No allocator lock may be held when calling this. `failures` is the number of failures read
+/// (through [`failures`](./fn.failures.html)) before the request. Returns `true` if the memory
+/// source failed since then, in which case the failed request should be retried, as the
+/// callbacks (possibly called by another thread) might have freed memory.
+#[inline]
+pub fn dispatch(failures: usize) -> bool {
+    let failed = FAILURES.load(atomic::Ordering::SeqCst) != failures;
+
+    // Fast path: nothing is pending.
+    if !PENDING.load(atomic::Ordering::Relaxed) || !PENDING.swap(false, atomic::Ordering::SeqCst) {
+        return failed;
+    }
+
+    let mark = PENDING_MARK.swap(0, atomic::Ordering::SeqCst);
+    if mark != 0 {
+        notify(Pressure::HighWater {
+            mark: mark,
+            heap_size: stats::stats().heap_size,
+        });
+    }
+
+    let failure = PENDING_FAILURE.swap(0, atomic::Ordering::SeqCst);
+    if failure != 0 {
+        notify(Pressure::SourceFailed {
+            size: failure - 1,
+        });
+    }
+
+    failed
+}
+
+/// Call the registered callbacks.
+fn notify(event: Pressure) {
+    // Copy the callbacks, such that they can be called without holding the lock.
+    let callbacks = *CALLBACKS.lock();
+
+    for callback in callbacks.iter().filter_map(|&x| x) {
+        log!(DEBUG, "Calling a memory pressure callback.");
+
+        callback(event);
+    }
+}
+
+/// Acquire the registry locks before forking.
+///
+/// The locks are kept until `fork_unlock` is called.
+pub fn fork_lock() {
+    mem::forget(MARKS.lock());
+    mem::forget(CALLBACKS.lock());
+}
+
+/// Release the registry locks after forking.
+///
+/// # Safety
+///
+/// This must be paired with a preceding call to `fork_lock`.
+pub unsafe fn fork_unlock() {
+    CALLBACKS.force_unlock();
+    MARKS.force_unlock();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        fn callback(_: Pressure) {}
+
+        assert!(add_pressure_callback(callback).is_ok());
+        assert!(remove_pressure_callback(callback).is_ok());
+        assert!(remove_pressure_callback(callback).is_err());
+
+        assert!(add_high_water_mark(0).is_err());
+    }
+
+    #[test]
+    fn test_failures() {
+        let before = failures();
+        assert!(!dispatch(before));
+
+        // The failure is reported, even if another thread dispatches the event.
+        source_failed(16);
+        source_succeeded();
+        assert!(dispatch(before));
+        assert!(dispatch(before));
+        assert!(!dispatch(failures()));
+    }
+}
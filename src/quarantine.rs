@@ -0,0 +1,232 @@
+//! Freed-memory quarantine.
+//!
+//! With address-ordered first fit, a freshly freed block is very likely to be handed out again
+//! right away, which makes use-after-free both exploitable and invisible. The quarantine delays
+//! the reuse by keeping freed blocks in a FIFO queue, until the byte budget is exceeded.
+
+use prelude::*;
+
+use core::{mem, ptr};
+
+use shim::config;
+
+/// A quarantine of freed blocks.
+///
+/// This is a ring buffer of blocks, ordered by the time of their free. Adjacent blocks are merged
+/// lazily, when the quarantine runs out of slots.
+pub struct Quarantine {
+    /// The ring buffer.
+    ///
+    /// Only the `len` blocks starting at `head` (wrapping around) are initialized.
+    blocks: [Block; config::QUARANTINE_SLOTS],
+    /// The index of the oldest block.
+    head: usize,
+    /// The number of quarantined blocks.
+    len: usize,
+    /// The total number of quarantined bytes.
+    bytes: usize,
+}
+
+impl Quarantine {
+    /// Create a new, empty quarantine.
+    pub fn new() -> Quarantine {
+        Quarantine {
+            blocks: unsafe {
+                // Blocks have no destructors, and the slots are never read before being written.
+                mem::uninitialized()
+            },
+            head: 0,
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Get the `n`'th oldest block.
+    fn slot(&mut self, n: usize) -> &mut Block {
+        &mut self.blocks[(self.head + n) % config::QUARANTINE_SLOTS]
+    }
+
+    /// Get the total number of quarantined bytes.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Find a quarantined block overlapping with some block.
+    ///
+    /// This is used for detecting double frees of quarantined blocks.
+    pub fn find_overlap(&self, block: &Block) -> Option<&Block> {
+        (0..self.len)
+            .map(|n| &self.blocks[(self.head + n) % config::QUARANTINE_SLOTS])
+            .find(|x| x.overlaps(block))
+    }
+
+    /// Put a block in quarantine.
+    ///
+    /// If the block cannot be quarantined (no free slots, even after merging adjacent blocks),
+    /// the oldest block is evicted and returned. Note that the budget might be exceeded
+    /// afterwards, see [`pop_excess`](#method.pop_excess).
+    pub fn push(&mut self, mut block: Block) -> Option<Block> {
+        // Short circuit on empty blocks.
+        if block.is_empty() { return None; }
+
+        log!(INTERNAL, "Quarantining {:?}.", block);
+
+        // When compiled with `security`, we zero this block right away, rather than on eviction.
+        block.sec_zero();
+        // When compiled with `poison`, we fill it with the free pattern, such that writes during
+        // the quarantine can be detected on eviction.
+        block.poison();
+        // Mark it free to the debugger, such that accesses during the quarantine are reported.
+        let block = block.mark_free();
+
+        let mut res = None;
+        if self.len == config::QUARANTINE_SLOTS {
+            // Try to make room by merging adjacent blocks.
+            self.coalesce();
+
+            if self.len == config::QUARANTINE_SLOTS {
+                res = self.pop();
+            }
+        }
+
+        // Append the block to the queue.
+        self.bytes += block.size();
+        self.len += 1;
+        let n = self.len - 1;
+        unsafe {
+            // The slot is uninitialized, so we must not read it.
+            ptr::write(self.slot(n), block);
+        }
+
+        res
+    }
+
+    /// Evict the oldest block.
+    pub fn pop(&mut self) -> Option<Block> {
+        if self.len == 0 {
+            None
+        } else {
+            let res = unsafe {
+                // The slot is initialized, and is marked uninitialized right after.
+                ptr::read(self.slot(0))
+            };
+
+            self.head = (self.head + 1) % config::QUARANTINE_SLOTS;
+            self.len -= 1;
+            self.bytes -= res.size();
+
+            Some(res)
+        }
+    }
+
+    /// Evict the oldest block, if the byte budget is exceeded.
+    pub fn pop_excess(&mut self) -> Option<Block> {
+        if self.bytes > config::QUARANTINE_BUDGET {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Merge adjacent blocks.
+    ///
+    /// The merged block takes the place of the left block in the queue, and the vacant slots are
+    /// removed while preserving the order.
+    fn coalesce(&mut self) {
+        log!(INTERNAL, "Coalescing the quarantine.");
+
+        // Merge every pair of adjacent blocks.
+        for i in 0..self.len {
+            for j in 0..self.len {
+                if i != j && !self.slot(i).is_empty() {
+                    let mut right = self.slot(j).pop();
+                    if self.slot(i).merge_right(&mut right).is_err() {
+                        // Not adjacent, put it back.
+                        *self.slot(j) = right;
+                    }
+                }
+            }
+        }
+
+        // Close the gaps left by the merged blocks.
+        let mut len = 0;
+        for i in 0..self.len {
+            if !self.slot(i).is_empty() {
+                let block = self.slot(i).pop();
+                *self.slot(len) = block;
+                len += 1;
+            }
+        }
+        self.len = len;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prelude::*;
+
+    use shim::config;
+
+    use super::*;
+
+    #[test]
+    fn test_fifo() {
+        let mut arr = [0u8; 64];
+        let block = unsafe {
+            Block::from_raw_parts(Pointer::new(&mut arr[0] as *mut u8), 64)
+        };
+        let (a, rest) = block.split(16);
+        let (_, rest) = rest.split(16);
+        let (b, _) = rest.split(16);
+
+        let mut quarantine = Quarantine::new();
+        assert!(quarantine.push(a).is_none());
+        assert!(quarantine.push(b).is_none());
+        assert_eq!(quarantine.bytes(), 32);
+        assert!(quarantine.pop_excess().is_none());
+
+        assert_eq!(*Pointer::from(quarantine.pop().unwrap()) as *const u8, &arr[0] as *const u8);
+        assert_eq!(*Pointer::from(quarantine.pop().unwrap()) as *const u8, &arr[32] as *const u8);
+        assert!(quarantine.pop().is_none());
+        assert_eq!(quarantine.bytes(), 0);
+    }
+
+    #[test]
+    fn test_overlap() {
+        let mut arr = [0u8; 64];
+        let block = unsafe {
+            Block::from_raw_parts(Pointer::new(&mut arr[0] as *mut u8), 64)
+        };
+        let (a, rest) = block.split(16);
+        let (b, _) = rest.split(16);
+
+        let mut quarantine = Quarantine::new();
+        assert!(quarantine.push(a).is_none());
+
+        let again = unsafe {
+            Block::from_raw_parts(Pointer::new(&mut arr[8] as *mut u8), 16)
+        };
+        assert_eq!(quarantine.find_overlap(&again).map(|x| x.size()), Some(16));
+        assert!(quarantine.find_overlap(&b).is_none());
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut arr = [0u8; 4 * config::QUARANTINE_SLOTS];
+        let mut rest = unsafe {
+            Block::from_raw_parts(Pointer::new(&mut arr[0] as *mut u8), arr.len())
+        };
+
+        let mut quarantine = Quarantine::new();
+        for _ in 0..config::QUARANTINE_SLOTS + 1 {
+            let (block, tail) = rest.split(2);
+            rest = tail;
+
+            // Every block is adjacent to the previous, so they can all be merged.
+            assert!(quarantine.push(block).is_none());
+        }
+
+        assert_eq!(quarantine.bytes(), 2 * (config::QUARANTINE_SLOTS + 1));
+        assert_eq!(quarantine.pop().unwrap().size(), 2 * config::QUARANTINE_SLOTS);
+    }
+}
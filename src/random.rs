@@ -0,0 +1,336 @@
+//! Pseudo-random number generation.
+//!
+//! In `security` mode, the allocation placement is randomized to make heap layouts harder to
+//! groom. The randomness comes from a per-thread xorshift generator, seeded from the OS.
+
+use core::cell::Cell;
+use core::sync::atomic::{self, AtomicU64};
+use core::{mem, ptr, slice};
+
+use shim::{config, syscalls};
+
+#[cfg(feature = "tls")]
+use tls;
+#[cfg(not(feature = "tls"))]
+use prelude::*;
+
+/// The seed override.
+///
+/// If non-zero, this is used for seeding new generators instead of the OS.
+static SEED_OVERRIDE: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "tls")]
+tls! {
+    /// The state of the thread's generator.
+    ///
+    /// Zero denotes an unseeded generator.
+    static STATE: Cell<u64> = Cell::new(0);
+}
+/// The state of the generator.
+///
+/// Zero denotes an unseeded generator.
+#[cfg(not(feature = "tls"))]
+static STATE: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// The `AT_RANDOM` entry type of the auxiliary vector.
+const AT_RANDOM: usize = 25;
+/// The maximal number of words read from the auxiliary vector.
+const MAX_AUXV: usize = 128;
+
+/// Read the random bytes, which the kernel passes to every process.
+///
+/// The address of these 16 bytes is the `AT_RANDOM` entry of the auxiliary vector, which is read
+/// from the auxiliary vector file of the shim. If it is unavailable, `None` is returned.
+fn at_random() -> Option<u64> {
+    let fd = syscalls::open_read(config::AUXV_PATH);
+    // Failing system calls return a negated error code.
+    if fd > !0 - 4096 { return None; }
+
+    // The auxiliary vector is a sequence of (type, value) pairs.
+    let mut auxv = [0usize; MAX_AUXV];
+    let buf = unsafe {
+        // Any bit pattern is a valid `usize`.
+        slice::from_raw_parts_mut(auxv.as_mut_ptr() as *mut u8, mem::size_of_val(&auxv))
+    };
+    let mut len = 0;
+    while len < buf.len() {
+        let n = syscalls::read(fd, &mut buf[len..]);
+        if n == 0 || n > !0 - 4096 { break; }
+
+        len += n;
+    }
+    syscalls::close(fd);
+
+    let words = len / mem::size_of::<usize>();
+    auxv[..words].chunks(2)
+        .take_while(|entry| entry[0] != 0)
+        .find(|entry| entry.len() == 2 && entry[0] == AT_RANDOM && entry[1] != 0)
+        .map(|entry| unsafe {
+            // The kernel guarantees that the bytes are valid for the lifetime of the process. They
+            // might be unaligned, so we copy them out.
+            let mut buf = [0u8; 8];
+            ptr::copy_nonoverlapping(entry[1] as *const u8, buf.as_mut_ptr(), buf.len());
+
+            // Any bit pattern is a valid `u64`.
+            mem::transmute::<[u8; 8], u64>(buf)
+        })
+}
+
+/// Obtain a seed.
+///
+/// This uses the seed override if set, and the OS otherwise. If the OS is unable to provide
+/// randomness, we fall back to the random bytes of the auxiliary vector, and then to the (ASLR'd)
+/// stack address.
+fn seed() -> u64 {
+    let seed = SEED_OVERRIDE.load(atomic::Ordering::SeqCst);
+    if seed != 0 {
+        return seed;
+    }
+
+    if let Some(seed) = config::RANDOM_SEED {
+        return seed;
+    }
+
+    let mut buf = [0u8; 8];
+    let res = if syscalls::getrandom(&mut buf) == buf.len() {
+        unsafe {
+            // Any bit pattern is a valid `u64`.
+            mem::transmute::<[u8; 8], u64>(buf)
+        }
+    } else if let Some(seed) = at_random() {
+        log!(NOTE, "Unable to obtain randomness from the OS. Using the auxiliary vector.");
+
+        seed
+    } else {
+        log!(WARNING, "Unable to obtain randomness from the OS.");
+
+        &buf as *const _ as usize as u64
+    };
+
+    // The generator is stuck at zero, so we make sure it is non-zero.
+    res | 1
+}
+
+/// Advance the generator and get the next number.
+///
+/// An unseeded (zero) state is seeded first.
+pub fn step(state: &Cell<u64>) -> u64 {
+    let mut x = state.get();
+    if x == 0 {
+        x = seed();
+    }
+
+    // xorshift64*.
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    state.set(x);
+
+    x.wrapping_mul(0x2545f4914f6cdd1d)
+}
+
+/// Get a pseudo-random number.
+pub fn next() -> u64 {
+    #[cfg(feature = "tls")]
+    {
+        STATE.with(step)
+    }
+
+    #[cfg(not(feature = "tls"))]
+    {
+        step(&STATE.lock())
+    }
+}
+
+/// Get a pseudo-random number below `n`.
+///
+/// # Panics
+///
+/// This panics if `n` is zero.
+#[allow(cast_possible_truncation)]
+pub fn below(n: usize) -> usize {
+    assert!(n != 0, "Empty random range.");
+
+    // The result is below `n`, hence the truncation is harmless.
+    (next() % n as u64) as usize
+}
+
+/// Override the seed of the pseudo-random number generators.
+///
+/// This makes the randomized placement (see the `security` feature) deterministic, which is
+/// useful for reproducing failures. The current thread's generator is reseeded right away, and
+/// generators of new threads use the seed as well. Setting it to zero restores seeding from the
+/// OS.
+pub fn set_random_seed(seed: u64) {
+    log!(NOTE, "Setting the random seed to {}.", seed);
+
+    SEED_OVERRIDE.store(seed, atomic::Ordering::SeqCst);
+
+    // Reset the generator, such that it is reseeded on next use.
+    #[cfg(feature = "tls")]
+    STATE.with(|state| state.set(0));
+    #[cfg(not(feature = "tls"))]
+    STATE.lock().set(0);
+}
+
+/// Acquire the generator lock before forking.
+///
+/// The lock is kept until `fork_unlock` is called.
+#[cfg(not(feature = "tls"))]
+pub fn fork_lock() {
+    mem::forget(STATE.lock());
+}
+
+/// Release the generator lock after forking.
+///
+/// # Safety
+///
+/// This must be paired with a preceding call to `fork_lock`.
+#[cfg(not(feature = "tls"))]
+pub unsafe fn fork_unlock() {
+    STATE.force_unlock();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "security")]
+    use std::vec::Vec as StdVec;
+
+    #[cfg(feature = "security")]
+    use bookkeeper::Allocator;
+    #[cfg(feature = "security")]
+    use prelude::{Block, Pointer};
+    #[cfg(feature = "security")]
+    use sim::{SimAllocator, Source};
+
+    /// Get the address of a block.
+    #[cfg(feature = "security")]
+    fn addr(block: &Block) -> usize {
+        *Pointer::from(block.empty_left()) as usize
+    }
+
+    /// Fragment the heap, such that several holes fit 64 bytes.
+    ///
+    /// The blocks between the holes are returned.
+    #[cfg(feature = "security")]
+    fn fragment(alloc: &mut SimAllocator) -> StdVec<Block> {
+        let mut kept = StdVec::new();
+        for n in 0..32 {
+            let block = alloc.alloc(128, 8);
+            if n % 2 == 0 {
+                alloc.free(block);
+            } else {
+                kept.push(block);
+            }
+        }
+
+        kept
+    }
+
+    #[test]
+    fn test_below() {
+        for n in 1..100 {
+            assert!(below(n) < n);
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let a = Cell::new(42);
+        let b = Cell::new(42);
+
+        for _ in 0..100 {
+            assert_eq!(step(&a), step(&b));
+        }
+        assert!(a.get() != 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_at_random() {
+        // The bytes are fixed for the lifetime of the process.
+        let seed = at_random().unwrap();
+        assert_eq!(at_random(), Some(seed));
+    }
+
+    #[test]
+    #[cfg(feature = "security")]
+    fn test_placement() {
+        let mut buf = [0u64; 65536];
+        let mut alloc = SimAllocator::new(unsafe {
+            Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+        });
+        let mut blocks = fragment(&mut alloc);
+
+        let mut skipped = false;
+        let mut offset = false;
+        for _ in 0..32 {
+            // Find the block, which the allocation is placed in.
+            let fitting: StdVec<_> = alloc.blocks().iter()
+                .filter(|x| x.fits(64, 8))
+                .map(|x| (addr(x), x.size()))
+                .collect();
+            let block = alloc.alloc(64, 8);
+            let (n, &(start, _)) = fitting.iter()
+                .enumerate()
+                .find(|&(_, &(start, size))| addr(&block) >= start && addr(&block) < start + size)
+                .expect("The allocation is not placed in a fitting block.");
+
+            skipped |= n != 0;
+            offset |= addr(&block) != start;
+
+            blocks.push(block);
+        }
+
+        // Both the block and the offset into it are chosen randomly.
+        assert!(skipped, "Always placed in the first fitting block.");
+        assert!(offset, "Always placed at the start of the block.");
+
+        for block in blocks {
+            alloc.free(block);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "security", feature = "tls"))]
+    fn test_seed() {
+        /// Get the offsets of a sequence of allocations into a fresh buffer.
+        fn placements() -> StdVec<usize> {
+            let mut buf = [0u64; 65536];
+            let start = buf.as_ptr() as usize;
+            let mut alloc = SimAllocator::new(unsafe {
+                Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+            });
+
+            let mut blocks = fragment(&mut alloc);
+            let mut res = StdVec::new();
+            for _ in 0..32 {
+                let block = alloc.alloc(64, 8);
+                res.push(addr(&block) - start);
+                blocks.push(block);
+            }
+
+            for block in blocks {
+                alloc.free(block);
+            }
+
+            res
+        }
+
+        // The same seed reproduces the placement.
+        set_random_seed(42);
+        let a = placements();
+        set_random_seed(42);
+        let b = placements();
+        assert_eq!(a, b);
+
+        // A different seed (most likely) does not.
+        set_random_seed(43);
+        let c = placements();
+        assert!(a != c);
+
+        set_random_seed(0);
+    }
+}
@@ -0,0 +1,550 @@
+//! Simulated memory.
+//!
+//! This provides a deterministic memory source, which serves segments from a buffer instead of
+//! moving the program break of the process. It emulates the BRK system call, but has a
+//! configurable capacity and page size, can fail on demand, and keeps a log of the calls.
+//!
+//! When compiled with `simulation`, the program break of the allocator is simulated by a global
+//! source over a static buffer (see [`lock`](./fn.lock.html)), e.g. to test how an application
+//! handles OOM conditions:
+//!
+//! ```rust,ignore
+//! let mut source = ralloc::sim::lock();
+//! source.fail_next(1);
+//! ```
+
+use prelude::*;
+
+use core::{cmp, mem, ptr};
+#[cfg(test)]
+use core::ops;
+
+use shim::config;
+#[cfg(feature = "simulation")]
+use sync;
+#[cfg(test)]
+use bookkeeper::{self, Bookkeeper, Allocator};
+#[cfg(test)]
+use fail::AllocError;
+
+/// The maximal number of events in the log.
+///
+/// When exceeded, the oldest events are dropped.
+const MAX_EVENTS: usize = 64;
+
+/// The buffer of the global source.
+#[cfg(feature = "simulation")]
+static mut BUFFER: [u64; config::SIM_BUFFER_SIZE / 8] = [0; config::SIM_BUFFER_SIZE / 8];
+/// The global source.
+///
+/// This simulates the program break of the allocator. It is bound to the buffer on first use.
+#[cfg(feature = "simulation")]
+static SOURCE: Mutex<Source> = Mutex::new(Source {
+    start: 0,
+    size: 0,
+    capacity: config::SIM_BUFFER_SIZE,
+    page_size: config::PAGE_SIZE,
+    brk: 0,
+    failing: 0,
+    events: [Event::Failed { size: 0 }; MAX_EVENTS],
+    len: 0,
+});
+
+/// A call to the memory source.
+///
+/// Offsets are relative to the start of the buffer, such that the log is deterministic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The break was grown.
+    Grow {
+        /// The offset of the old break.
+        offset: usize,
+        /// The number of bytes acquired.
+        size: usize,
+    },
+    /// The break was shrunk.
+    Shrink {
+        /// The offset of the new break.
+        offset: usize,
+        /// The number of bytes released.
+        size: usize,
+    },
+    /// Growing the break failed.
+    Failed {
+        /// The number of bytes requested.
+        size: usize,
+    },
+}
+
+/// A simulated memory source.
+///
+/// This manages a program break inside a buffer. The break can be moved within the capacity, and
+/// the memory between the start of the buffer and the break is "acquired".
+pub struct Source {
+    /// The address of the buffer.
+    start: usize,
+    /// The size of the buffer.
+    size: usize,
+    /// The maximal number of bytes, which can be acquired.
+    capacity: usize,
+    /// The page size.
+    ///
+    /// Acquired memory is mapped page-wise, i.e. the capacity must hold the break rounded up to a
+    /// page.
+    page_size: usize,
+    /// The offset of the break.
+    brk: usize,
+    /// The number of upcoming failing grows (`!0` for "all of them").
+    failing: usize,
+    /// The event log.
+    events: [Event; MAX_EVENTS],
+    /// The number of events in the log.
+    len: usize,
+}
+
+impl Source {
+    /// Create a source over some buffer.
+    ///
+    /// The capacity is the size of the buffer, and the page size is the one of the shim.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must be valid for reads and writes of `size` bytes, for as long as the source (or
+    /// any memory acquired from it) is in use.
+    pub unsafe fn new(ptr: *mut u8, size: usize) -> Source {
+        Source {
+            start: ptr as usize,
+            size: size,
+            capacity: size,
+            page_size: config::PAGE_SIZE,
+            brk: 0,
+            failing: 0,
+            events: [Event::Failed { size: 0 }; MAX_EVENTS],
+            len: 0,
+        }
+    }
+
+    /// Set the maximal number of bytes, which can be acquired.
+    ///
+    /// This is bounded by the size of the buffer. Memory already acquired is not affected.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = cmp::min(capacity, self.size);
+    }
+
+    /// Get the maximal number of bytes, which can be acquired.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Set the page size.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the page size is not a power of two.
+    pub fn set_page_size(&mut self, page_size: usize) {
+        assert!(page_size.is_power_of_two(), "The page size must be a power of two.");
+
+        self.page_size = page_size;
+    }
+
+    /// Get the page size.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Make the next `n` grows fail.
+    ///
+    /// `!0` makes every grow fail, and `0` stops failing.
+    pub fn fail_next(&mut self, n: usize) {
+        self.failing = n;
+    }
+
+    /// Get the number of bytes acquired.
+    pub fn used(&self) -> usize {
+        self.brk
+    }
+
+    /// Get the number of bytes mapped, i.e. the acquired bytes rounded up to a page.
+    pub fn mapped(&self) -> usize {
+        (self.brk + self.page_size - 1) & !(self.page_size - 1)
+    }
+
+    /// Get the event log, oldest first.
+    pub fn events(&self) -> &[Event] {
+        &self.events[..self.len]
+    }
+
+    /// Clear the event log.
+    pub fn clear_events(&mut self) {
+        self.len = 0;
+    }
+
+    /// Log an event.
+    fn log(&mut self, event: Event) {
+        if self.len == MAX_EVENTS {
+            // Drop the oldest event.
+            for i in 1..MAX_EVENTS {
+                self.events[i - 1] = self.events[i];
+            }
+            self.len -= 1;
+        }
+
+        self.events[self.len] = event;
+        self.len += 1;
+    }
+
+    /// Set the break.
+    ///
+    /// This emulates the BRK system call: A null pointer queries the break. On success, the new
+    /// break is returned. On failure, the old break is returned.
+    pub fn brk(&mut self, ptr: *const u8) -> *const u8 {
+        let old = self.start + self.brk;
+
+        if ptr.is_null() || ptr as usize == old {
+            return old as *const u8;
+        }
+
+        if (ptr as usize) < self.start {
+            // The break cannot move before the start of the buffer.
+            return old as *const u8;
+        }
+
+        let new = ptr as usize - self.start;
+        if new < self.brk {
+            self.log(Event::Shrink {
+                offset: new,
+                size: self.brk - new,
+            });
+        } else {
+            let size = new - self.brk;
+            let mapped = new.checked_add(self.page_size - 1).map(|x| x & !(self.page_size - 1));
+
+            if self.failing != 0 || mapped.map_or(true, |x| x > self.capacity) {
+                // Logging...
+                log!(NOTE, "Simulating failure to acquire {} bytes.", size);
+
+                if self.failing != !0 {
+                    self.failing = self.failing.saturating_sub(1);
+                }
+                self.log(Event::Failed { size: size });
+
+                return old as *const u8;
+            }
+
+            self.log(Event::Grow {
+                offset: self.brk,
+                size: size,
+            });
+        }
+
+        self.brk = new;
+
+        ptr
+    }
+
+    /// Move the break by `delta` bytes.
+    ///
+    /// On success, the old break is returned.
+    #[allow(cast_possible_wrap)]
+    pub fn sbrk(&mut self, delta: isize) -> Result<*mut u8, ()> {
+        let old = self.brk(ptr::null());
+        let expected = (old as usize).wrapping_add(delta as usize) as *const u8;
+
+        if self.brk(expected) == expected {
+            Ok(old as *mut u8)
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Lock the global source.
+///
+/// With `simulation`, this is the program break of the allocator. Note that the allocator takes
+/// this lock as well, so it must not be held while allocating.
+#[cfg(feature = "simulation")]
+pub fn lock() -> sync::MutexGuard<'static, Source> {
+    let mut res = SOURCE.lock();
+
+    // Bind the source to the buffer.
+    if res.start == 0 {
+        res.start = unsafe {
+            // Only the address is taken.
+            BUFFER.as_ptr() as usize
+        };
+        res.size = config::SIM_BUFFER_SIZE;
+    }
+
+    res
+}
+
+/// Set the simulated program break.
+///
+/// This emulates the BRK system call on the global source.
+#[cfg(feature = "simulation")]
+#[inline]
+pub fn brk(ptr: *const u8) -> *const u8 {
+    lock().brk(ptr)
+}
+
+/// Acquire the lock of the global source before forking.
+///
+/// The lock is kept until `fork_unlock` is called.
+#[cfg(feature = "simulation")]
+pub fn fork_lock() {
+    mem::forget(SOURCE.lock());
+}
+
+/// Release the lock of the global source after forking.
+///
+/// # Safety
+///
+/// This must be paired with a preceding call to `fork_lock`.
+#[cfg(feature = "simulation")]
+pub unsafe fn fork_unlock() {
+    SOURCE.force_unlock();
+}
+
+/// An allocator acquiring memory from a source.
+///
+/// This allows testing the bookkeeper deterministically, independent of the global allocator.
+#[cfg(test)]
+pub struct SimAllocator {
+    /// The inner bookkeeper.
+    inner: Bookkeeper,
+    /// The memory source.
+    pub source: Source,
+}
+
+#[cfg(test)]
+impl SimAllocator {
+    /// Create an allocator acquiring memory from `source`.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the initial segment cannot be acquired.
+    pub fn new(mut source: Source) -> SimAllocator {
+        let size = 4 * bookkeeper::EXTRA_ELEMENTS * mem::size_of::<Block>();
+        let (aligner, initial_segment, excessive) = acquire(&mut source, size, mem::align_of::<Block>())
+            .expect("Unable to acquire the initial segment.");
+
+        let mut res = SimAllocator {
+            inner: Bookkeeper::new(unsafe {
+                // The segment was just acquired, and is aligned for blocks.
+                Vec::from_raw_parts(initial_segment, 0)
+            }),
+            source: source,
+        };
+
+        // Free the secondary space.
+        res.push(aligner);
+        res.push(excessive);
+
+        res
+    }
+
+    /// Release the top block to the source.
+    ///
+    /// The number of bytes released is returned.
+    #[allow(cast_possible_wrap)]
+    pub fn memtrim(&mut self) -> usize {
+        let block = match self.pop() {
+            Some(block) => block,
+            None => return 0,
+        };
+        let size = block.size();
+
+        if self.source.brk(ptr::null()) == *Pointer::from(block.empty_right()) as *const u8 {
+            self.source.sbrk(-(size as isize)).unwrap();
+
+            size
+        } else {
+            // Put the block back.
+            self.push_prepared(block);
+
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+impl ops::Deref for SimAllocator {
+    type Target = Bookkeeper;
+
+    fn deref(&self) -> &Bookkeeper {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+impl ops::DerefMut for SimAllocator {
+    fn deref_mut(&mut self) -> &mut Bookkeeper {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+impl Allocator for SimAllocator {
+    fn alloc_fresh(&mut self, size: usize, align: usize) -> Result<Block, AllocError> {
+        // Make sure that pushing the aligner and the excessive block won't need to reserve.
+        if let Some(x) = unborrow!(self.reserve(self.len() + 2))? {
+            self.free(x);
+        }
+
+        let (aligner, res, excessive) = acquire(&mut self.source, size, align)?;
+        self.push(aligner);
+        self.push(excessive);
+
+        Ok(res)
+    }
+
+    fn extend_fresh(&mut self, end: &Block, size: usize) -> Result<Block, AllocError> {
+        if self.source.brk(ptr::null()) != *Pointer::from(end.empty_left()) as *const u8 {
+            return Err(AllocError::OutOfMemory);
+        }
+
+        let delta = if size > isize::max_value() as usize {
+            return Err(AllocError::SizeOverflow);
+        } else {
+            size as isize
+        };
+
+        let segment = self.source.sbrk(delta).map_err(|()| AllocError::OutOfMemory)?;
+
+        Ok(unsafe {
+            // The segment was just acquired.
+            Block::from_raw_parts(Pointer::new(segment), size)
+        })
+    }
+
+    fn is_global(&self) -> bool {
+        true
+    }
+}
+
+/// Acquire a segment of exactly `size + align` bytes from a source.
+///
+/// The segment is split into an aligner, the aligned block of size `size`, and the excessive
+/// space, like `BrkLock::canonical_brk` does (without extra space).
+#[cfg(test)]
+fn acquire(source: &mut Source, size: usize, align: usize) -> Result<(Block, Block, Block), AllocError> {
+    let brk_size = size.checked_add(align).ok_or(AllocError::SizeOverflow)?;
+    let delta = if brk_size > isize::max_value() as usize {
+        return Err(AllocError::SizeOverflow);
+    } else {
+        brk_size as isize
+    };
+
+    let segment = source.sbrk(delta).map_err(|()| AllocError::OutOfMemory)?;
+    let (aligner, rest) = unsafe {
+        // The segment was just acquired.
+        Block::from_raw_parts(Pointer::new(segment), brk_size)
+    }.align(align).unwrap();
+    let (res, excessive) = rest.split(size);
+
+    Ok((aligner, res, excessive))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_brk() {
+        let mut buf = [0u8; 1024];
+        let mut source = unsafe { Source::new(buf.as_mut_ptr(), buf.len()) };
+        source.set_page_size(256);
+
+        let start = source.sbrk(100).unwrap();
+        assert_eq!(start, buf.as_mut_ptr());
+        assert_eq!(source.used(), 100);
+        assert_eq!(source.mapped(), 256);
+
+        // Fail on demand.
+        source.fail_next(1);
+        assert!(source.sbrk(100).is_err());
+        assert!(source.sbrk(100).is_ok());
+
+        // Exceed the capacity (in pages).
+        source.set_capacity(512);
+        assert!(source.sbrk(400).is_err());
+
+        source.sbrk(-150).unwrap();
+        assert_eq!(source.used(), 50);
+
+        assert_eq!(source.events(), &[
+            Event::Grow { offset: 0, size: 100 },
+            Event::Failed { size: 100 },
+            Event::Grow { offset: 100, size: 100 },
+            Event::Failed { size: 400 },
+            Event::Shrink { offset: 50, size: 150 },
+        ]);
+
+        source.clear_events();
+        assert!(source.events().is_empty());
+    }
+
+    #[test]
+    fn test_grow_up() {
+        let mut buf = [0u8; 1024];
+        let mut source = unsafe { Source::new(buf.as_mut_ptr(), buf.len()) };
+
+        let brk1 = source.sbrk(5).unwrap();
+        let brk2 = source.sbrk(100).unwrap();
+
+        assert!(brk1 < brk2);
+    }
+
+    #[test]
+    fn test_acquire_ordered() {
+        let mut buf = [0u8; 1024];
+        let mut source = unsafe { Source::new(buf.as_mut_ptr(), buf.len()) };
+
+        let (aligner, res, excessive) = acquire(&mut source, 20, 1).unwrap();
+
+        assert!(aligner <= res);
+        assert!(res <= excessive);
+    }
+
+    #[test]
+    fn test_alloc() {
+        let mut buf = [0u64; 8192];
+        let mut alloc = SimAllocator::new(unsafe {
+            Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+        });
+        alloc.source.clear_events();
+
+        let a = alloc.alloc(1000, 8);
+        let b = alloc.alloc(3000, 64);
+        assert!(b.aligned_to(64));
+        assert!(alloc.source.used() >= 4000);
+
+        alloc.free(a);
+        alloc.free(b);
+
+        // Everything is free, so the top of the break can be released.
+        assert!(alloc.memtrim() > 0);
+        match alloc.source.events().last() {
+            Some(&Event::Shrink { .. }) => (),
+            x => panic!("Expected shrink, got {:?}.", x),
+        }
+    }
+
+    #[test]
+    fn test_oom() {
+        let mut buf = [0u64; 4096];
+        let mut alloc = SimAllocator::new(unsafe {
+            Source::new(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+        });
+
+        // Failing acquisition leaves the allocator intact.
+        alloc.source.fail_next(1);
+        assert_eq!(alloc.try_alloc(4096, 8).err(), Some(AllocError::OutOfMemory));
+        let block = alloc.try_alloc(4096, 8).unwrap();
+
+        // Exceeding the capacity.
+        assert_eq!(alloc.try_alloc(1 << 20, 8).err(), Some(AllocError::OutOfMemory));
+
+        alloc.free(block);
+    }
+}
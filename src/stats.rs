@@ -0,0 +1,124 @@
+//! Allocator statistics.
+//!
+//! This keeps track of the memory usage of the allocator, such that it can be queried by the
+//! application, and handed to the OOM handlers.
+
+use core::sync::atomic::{self, AtomicUsize};
+
+/// The number of bytes acquired from the OS.
+static HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// The number of bytes allocated, but not yet freed.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+/// The total number of allocations.
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// The number of BRK system calls.
+static BRK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Subtract from a counter, saturating at zero.
+///
+/// Foreign buffers (e.g. from `sbrk` or other allocators) can be released through ralloc, in which
+/// case more is subtracted than was added. Rather than wrapping around, the counter stays at zero.
+#[inline]
+fn saturating_sub(counter: &AtomicUsize, n: usize) {
+    let mut old = counter.load(atomic::Ordering::Relaxed);
+
+    loop {
+        let prev = counter.compare_and_swap(old, old.saturating_sub(n), atomic::Ordering::Relaxed);
+        if prev == old { break; }
+
+        old = prev;
+    }
+}
+
+/// A snapshot of the allocator statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// The number of bytes acquired from the OS.
+    pub heap_size: usize,
+    /// The number of bytes allocated, but not yet freed.
+    pub allocated: usize,
+    /// The total number of allocations.
+    pub allocations: usize,
+    /// The number of BRK system calls made to move the program break.
+    pub brk_calls: usize,
+}
+
+/// Get a snapshot of the allocator statistics.
+///
+/// Note that the counters are updated independently, hence the snapshot might be slightly
+/// inconsistent when other threads are allocating concurrently.
+pub fn stats() -> Stats {
+    Stats {
+        heap_size: HEAP_SIZE.load(atomic::Ordering::Relaxed),
+        allocated: ALLOCATED.load(atomic::Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(atomic::Ordering::Relaxed),
+        brk_calls: BRK_CALLS.load(atomic::Ordering::Relaxed),
+    }
+}
+
+/// Register that the heap has grown by `size` bytes.
+#[inline]
+pub fn heap_grown(size: usize) {
+    HEAP_SIZE.fetch_add(size, atomic::Ordering::Relaxed);
+}
+
+/// Register that the heap has shrunk by `size` bytes.
+#[inline]
+pub fn heap_shrunk(size: usize) {
+    saturating_sub(&HEAP_SIZE, size);
+}
+
+/// Register an allocation of `size` bytes.
+#[inline]
+pub fn allocated(size: usize) {
+    ALLOCATED.fetch_add(size, atomic::Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, atomic::Ordering::Relaxed);
+}
+
+/// Register a free of `size` bytes.
+#[inline]
+pub fn freed(size: usize) {
+    saturating_sub(&ALLOCATED, size);
+}
+
+/// Register a reallocation from `old_size` to `size` bytes.
+#[inline]
+pub fn reallocated(old_size: usize, size: usize) {
+    if size >= old_size {
+        ALLOCATED.fetch_add(size - old_size, atomic::Ordering::Relaxed);
+    } else {
+        saturating_sub(&ALLOCATED, old_size - size);
+    }
+}
+
+/// Register a BRK system call.
+#[inline]
+pub fn brk_called() {
+    BRK_CALLS.fetch_add(1, atomic::Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allocated() {
+        let before = stats().allocations;
+
+        allocated(100);
+        reallocated(100, 200);
+        freed(200);
+
+        assert!(stats().allocations > before);
+    }
+
+    #[test]
+    fn test_saturating_sub() {
+        let counter = AtomicUsize::new(10);
+
+        saturating_sub(&counter, 4);
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 6);
+        saturating_sub(&counter, 100);
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 0);
+    }
+}
@@ -49,6 +49,17 @@ impl<T> Mutex<T> {
             mutex: self,
         }
     }
+
+    /// Forcibly unlock this mutex.
+    ///
+    /// # Safety
+    ///
+    /// This is only safe, if the lock is held and its guard was forgotten (e.g. to keep the lock
+    /// across `fork`).
+    #[inline]
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, atomic::Ordering::SeqCst);
+    }
 }
 
 /// A mutex guard.
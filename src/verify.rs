@@ -0,0 +1,74 @@
+//! Heap verification.
+//!
+//! In contrast to the consistency checks of the bookkeeper, which only run in debug mode, this
+//! verifies the block pools on demand, or periodically (see `Param::VerifyInterval`).
+
+use prelude::*;
+
+use core::sync::atomic::{self, AtomicUsize};
+
+use config::{self, Param};
+use fail::{self, Corruption, HeapError};
+use {allocator, brk, emergency};
+
+/// The number of allocator operations since the last verification.
+static OPERATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Verify the heap.
+///
+/// This verifies the block pools of the current thread and the global allocator for sortedness,
+/// adjacency, byte count consistency and capacity headroom, and that no free block extends past
+/// the program break. It runs in release mode too.
+///
+/// # Errors
+///
+/// The first inconsistency found is returned.
+pub fn verify_heap() -> Result<(), HeapError> {
+    // Logging.
+    log!(NOTE, "Verifying the heap.");
+
+    let mut res = Ok(());
+
+    allocator::for_each_bookkeeper(|bk, _| {
+        if res.is_err() { return; }
+
+        res = bk.verify();
+
+        // The break is read while the bookkeeper is locked, hence it cannot have acquired memory
+        // beyond it in the meantime.
+        let brk = *brk::current_brk() as usize;
+        if let Some(block) = bk.blocks().last() {
+            let ptr = *Pointer::from(block.empty_left());
+
+            if res.is_ok() && ptr as usize + block.size() > brk {
+                res = Err(HeapError::PastBreak {
+                    ptr: ptr,
+                    size: block.size(),
+                    brk: brk as *const u8,
+                });
+            }
+        }
+    });
+
+    res
+}
+
+/// Count an allocator operation, and verify the heap periodically.
+///
+/// Every `Param::VerifyInterval` operations, the heap is verified, and inconsistencies are
+/// reported to the heap corruption handler.
+#[inline]
+pub fn tick() {
+    let interval = config::get(Param::VerifyInterval);
+
+    // Nested calls cannot access the allocator in use.
+    if interval == 0 || emergency::entered() { return; }
+
+    if OPERATIONS.fetch_add(1, atomic::Ordering::Relaxed) + 1 >= interval {
+        OPERATIONS.store(0, atomic::Ordering::Relaxed);
+
+        if let Err(err) = verify_heap() {
+            fail::corruption(Corruption::InconsistentHeap(err));
+        }
+    }
+}
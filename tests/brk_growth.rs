@@ -0,0 +1,19 @@
+extern crate ralloc;
+
+// The BRK calls are counted globally, hence this is kept as a single test.
+#[test]
+fn brk_growth() {
+    let before = ralloc::stats().brk_calls;
+
+    // Grow the heap by 16 MB in small steps.
+    let ptrs: Vec<_> = (0..16384).map(|_| ralloc::alloc(1024, 8)).collect();
+
+    // Without adaptive growth, this would take thousands of calls.
+    let calls = ralloc::stats().brk_calls - before;
+    assert!(calls > 0);
+    assert!(calls < 256, "{} BRK calls", calls);
+
+    for ptr in ptrs {
+        unsafe { ralloc::free(ptr, 1024); }
+    }
+}
@@ -0,0 +1,35 @@
+extern crate ralloc;
+
+mod util;
+
+use ralloc::config::{self, Param};
+
+#[test]
+fn config() {
+    let old = config::get(Param::LocalMemtrimLimit);
+
+    // Trim aggressively.
+    assert_eq!(config::set(Param::LocalMemtrimLimit, 0), old);
+    assert_eq!(config::get(Param::LocalMemtrimLimit), 0);
+
+    util::multiply(|| {
+        let mut vec = Vec::new();
+
+        for i in 0..1000 {
+            vec.push(Box::new(i));
+        }
+
+        for (n, i) in vec.iter().enumerate() {
+            assert_eq!(**i, n);
+        }
+    });
+
+    config::set(Param::LocalMemtrimLimit, old);
+}
+
+#[test]
+fn key() {
+    assert_eq!(Param::MemtrimLimit.key(), "memtrim_limit");
+    assert_eq!(Param::from_key(b"log_level"), Some(Param::LogLevel));
+    assert_eq!(Param::from_key(b"arenas"), None);
+}
@@ -0,0 +1,33 @@
+extern crate ralloc;
+
+fn panic(_: ralloc::Corruption) {
+    panic!("Heap corruption detected.");
+}
+
+#[test]
+#[should_panic]
+#[cfg(any(debug_assertions, feature = "hardened"))]
+fn double_free() {
+    ralloc::set_corruption_handler(panic);
+
+    let ptr = ralloc::alloc(16, 8);
+
+    unsafe {
+        ralloc::free(ptr, 16);
+        ralloc::free(ptr, 16);
+    }
+}
+
+#[test]
+#[should_panic]
+#[cfg(any(debug_assertions, feature = "hardened"))]
+fn overlapping_free() {
+    ralloc::set_corruption_handler(panic);
+
+    let ptr = ralloc::alloc(64, 8);
+
+    unsafe {
+        ralloc::free(ptr.offset(16), 32);
+        ralloc::free(ptr, 32);
+    }
+}
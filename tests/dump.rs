@@ -0,0 +1,51 @@
+extern crate ralloc;
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
+
+extern {
+    fn pipe(fds: *mut i32) -> i32;
+}
+
+fn dump(format: ralloc::Format) -> String {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { pipe(fds.as_mut_ptr()) }, 0);
+    let (mut read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // Read concurrently, such that the dump can't fill the pipe.
+    let reader = thread::spawn(move || {
+        let mut res = String::new();
+        read.read_to_string(&mut res).unwrap();
+
+        res
+    });
+
+    ralloc::dump_heap(write.as_raw_fd() as usize, format).unwrap();
+    // Close the pipe, ending the read.
+    drop(write);
+
+    reader.join().unwrap()
+}
+
+#[test]
+fn dump_heap() {
+    // Leave a hole in the heap.
+    let a = ralloc::alloc(64, 8);
+    let b = ralloc::alloc(64, 8);
+    unsafe { ralloc::free(a, 64); }
+
+    let text = dump(ralloc::Format::Text);
+    assert!(text.starts_with("ralloc heap dump\n"));
+    assert!(text.contains("(global):"));
+    assert!(text.contains("  free 0x"));
+    assert!(text.contains("program break: 0x"));
+
+    let dot = dump(ralloc::Format::Graphviz);
+    assert!(dot.starts_with("digraph heap {\n"));
+    assert!(dot.contains("subgraph cluster_"));
+    assert!(dot.ends_with("}\n"));
+
+    unsafe { ralloc::free(b, 64); }
+}
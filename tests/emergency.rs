@@ -0,0 +1,42 @@
+extern crate ralloc;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static CALLED: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn corruption(_: ralloc::Corruption) {
+    // A nested call entering and leaving the allocator keeps the thread inside it.
+    ralloc::compact();
+
+    unsafe {
+        let ptr = ralloc::alloc(32, 8);
+
+        // Buffers from the emergency arena can't be reallocated inplace, and aren't counted.
+        assert!(ralloc::realloc_inplace(ptr, 32, 16).is_err());
+        ALLOCATIONS.store(ralloc::stats().allocations, Ordering::SeqCst);
+
+        ralloc::free(ptr, 32);
+    }
+
+    CALLED.store(true, Ordering::SeqCst);
+}
+
+// The handler is global, hence this is kept as a single test.
+#[test]
+#[cfg(any(debug_assertions, feature = "hardened"))]
+fn alloc_while_entered() {
+    ralloc::set_corruption_handler(corruption);
+
+    let ptr = ralloc::alloc(16, 8);
+    let allocations = ralloc::stats().allocations;
+
+    unsafe {
+        // The double free calls the handler from inside the allocator.
+        ralloc::free(ptr, 16);
+        ralloc::free(ptr, 16);
+    }
+
+    assert!(CALLED.load(Ordering::SeqCst));
+    assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), allocations);
+}
@@ -0,0 +1,23 @@
+extern crate ralloc;
+
+// The buffer must stay on the top of the heap, hence this is kept as a single test.
+#[test]
+fn extend() {
+    let ptr = ralloc::alloc(16 << 20, 8);
+
+    // Release the excessive bytes after the buffer, such that it ends at the program break.
+    ralloc::trim();
+
+    unsafe {
+        *ptr = 42;
+
+        // The missing bytes are acquired right after the buffer.
+        assert!(ralloc::realloc_inplace(ptr, 16 << 20, 32 << 20).is_ok());
+        assert_eq!(*ptr, 42);
+
+        // The grown tail is writable.
+        *ptr.offset((32 << 20) - 1) = 1;
+
+        ralloc::free(ptr, 32 << 20);
+    }
+}
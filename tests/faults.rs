@@ -0,0 +1,82 @@
+#![cfg(feature = "fault_injection")]
+
+extern crate ralloc;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ralloc::{faults, AllocError, OomAction, OomInfo};
+
+/// A size, which is never served without acquiring memory.
+const HUGE: usize = 1 << 24;
+
+/// The number of calls to the OOM handler.
+static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+fn retry(info: &OomInfo) -> OomAction {
+    assert_eq!(info.error, AllocError::OutOfMemory);
+    HANDLED.fetch_add(1, Ordering::SeqCst);
+
+    // Stop failing, and retry.
+    faults::reset();
+
+    OomAction::Retry
+}
+
+#[test]
+fn faults() {
+    // Failing large acquisitions.
+    faults::fail_sizes_above(1 << 20);
+    assert_eq!(ralloc::try_alloc(HUGE, 8).err(), Some(AllocError::OutOfMemory));
+    assert!(faults::injected() > 0);
+    faults::check_consistency();
+
+    // Small requests still succeed.
+    let ptr = ralloc::try_alloc(64, 8).unwrap().as_ptr();
+    unsafe { ralloc::free(ptr, 64); }
+    faults::check_consistency();
+    faults::reset();
+
+    // Failing every acquisition.
+    faults::fail_after(0);
+    assert_eq!(ralloc::try_alloc(HUGE, 8).err(), Some(AllocError::OutOfMemory));
+    unsafe {
+        let ptr = ralloc::alloc(16, 8);
+        assert_eq!(ralloc::try_realloc(ptr, 16, HUGE, 8).err(), Some(AllocError::OutOfMemory));
+        ralloc::free(ptr, 16);
+    }
+    faults::check_consistency();
+    faults::reset();
+
+    // Failing every other acquisition.
+    faults::fail_every(2);
+    let mut ptrs = Vec::new();
+    for _ in 0..8 {
+        match ralloc::try_alloc(HUGE, 8) {
+            Ok(ptr) => ptrs.push(ptr.as_ptr()),
+            Err(err) => assert_eq!(err, AllocError::OutOfMemory),
+        }
+
+        faults::check_consistency();
+    }
+    for ptr in ptrs {
+        unsafe { ralloc::free(ptr, HUGE); }
+    }
+    faults::check_consistency();
+    faults::reset();
+
+    // The controls compose: Every other acquisition fails, and so does every one after the third.
+    faults::fail_after(3);
+    faults::fail_every(2);
+    let res: Vec<_> = (0..5).map(|_| faults::inject(1).is_ok()).collect();
+    assert_eq!(res, [true, false, true, false, false]);
+    faults::reset();
+
+    // The infallible functions go through the OOM handlers.
+    ralloc::set_thread_oom_info_handler(retry);
+    faults::fail_after(0);
+    let ptr = ralloc::alloc(HUGE, 8);
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+    assert_eq!(faults::injected(), 0);
+    unsafe { ralloc::free(ptr, HUGE); }
+    faults::check_consistency();
+}
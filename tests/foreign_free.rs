@@ -0,0 +1,18 @@
+extern crate ralloc;
+
+fn panic(_: ralloc::Corruption) {
+    panic!("Heap corruption detected.");
+}
+
+#[test]
+#[should_panic]
+#[cfg(feature = "validate")]
+fn stack_free() {
+    ralloc::set_corruption_handler(panic);
+
+    let mut buf = [0u8; 32];
+
+    unsafe {
+        ralloc::free(buf.as_mut_ptr(), 32);
+    }
+}
@@ -0,0 +1,16 @@
+extern crate ralloc;
+
+fn ignore(_: ralloc::Corruption) {}
+
+#[test]
+#[cfg(feature = "validate")]
+fn stack_realloc() {
+    ralloc::set_corruption_handler(ignore);
+
+    let mut buf = [0u8; 32];
+
+    unsafe {
+        assert!(ralloc::realloc_inplace(buf.as_mut_ptr(), 32, 16).is_err());
+        assert!(ralloc::realloc(buf.as_mut_ptr(), 32, 64, 1).is_null());
+    }
+}
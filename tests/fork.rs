@@ -0,0 +1,58 @@
+#![cfg(target_os = "linux")]
+
+extern crate ralloc;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+extern {
+    fn fork() -> i32;
+    fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+    fn _exit(status: i32) -> !;
+}
+
+#[test]
+fn fork_while_allocating() {
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Keep another thread hammering the allocator, such that it is likely to hold a lock.
+    let handle = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                let ptr = ralloc::alloc(1 << 16, 8);
+                unsafe {
+                    ralloc::free(ptr, 1 << 16);
+                }
+            }
+        })
+    };
+
+    for _ in 0..16 {
+        unsafe {
+            ralloc::prefork();
+            let pid = fork();
+            assert!(pid >= 0);
+
+            if pid == 0 {
+                ralloc::postfork_child();
+
+                // This would deadlock, if a lock was left held.
+                let ptr = ralloc::alloc(1 << 20, 8);
+                ralloc::free(ptr, 1 << 20);
+
+                _exit(0);
+            }
+
+            ralloc::postfork_parent();
+
+            let mut status = 1;
+            assert_eq!(waitpid(pid, &mut status, 0), pid);
+            assert_eq!(status, 0);
+        }
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+}
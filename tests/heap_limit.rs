@@ -0,0 +1,17 @@
+extern crate ralloc;
+
+use ralloc::AllocError;
+
+// This is kept in a separate binary, since the limit affects every thread.
+#[test]
+fn heap_limit() {
+    // Forbid growing the heap.
+    ralloc::set_heap_limit(ralloc::stats().heap_size);
+
+    let res = ralloc::try_alloc(16 << 20, 1).err();
+
+    // Lift the limit again, before anything else allocates.
+    ralloc::set_heap_limit(!0);
+
+    assert_eq!(res, Some(AllocError::LimitExceeded));
+}
@@ -0,0 +1,27 @@
+extern crate ralloc;
+
+use ralloc::AllocError;
+
+#[test]
+fn thread_limit() {
+    let usage = ralloc::thread_usage();
+    ralloc::set_thread_limit(usage + 1024);
+
+    assert_eq!(ralloc::try_alloc(4096, 1).err(), Some(AllocError::LimitExceeded));
+
+    let ptr = ralloc::try_alloc(512, 1).unwrap().as_ptr();
+    assert_eq!(ralloc::thread_usage(), usage + 512);
+
+    unsafe {
+        // Growing beyond the limit must leave the buffer intact.
+        *ptr = 0xAA;
+        assert_eq!(ralloc::try_realloc(ptr, 512, 2048, 1).err(), Some(AllocError::LimitExceeded));
+        assert_eq!(*ptr, 0xAA);
+
+        ralloc::free(ptr, 512);
+    }
+
+    assert_eq!(ralloc::thread_usage(), usage);
+
+    ralloc::set_thread_limit(!0);
+}
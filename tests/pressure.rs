@@ -0,0 +1,52 @@
+extern crate ralloc;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ralloc::Pressure;
+
+static HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+static FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+fn callback(event: Pressure) {
+    match event {
+        Pressure::HighWater { .. } => {
+            HIGH_WATER.fetch_add(1, Ordering::SeqCst);
+        },
+        Pressure::SourceFailed { .. } => {
+            FAILURES.fetch_add(1, Ordering::SeqCst);
+        },
+    }
+}
+
+// The events are global, hence this is kept as a single test.
+#[test]
+fn pressure() {
+    ralloc::add_pressure_callback(callback).unwrap();
+
+    // Crossing a high-water mark.
+    ralloc::add_high_water_mark(ralloc::stats().heap_size + 1).unwrap();
+
+    let ptr = ralloc::alloc(1 << 20, 1);
+    assert!(HIGH_WATER.load(Ordering::SeqCst) > 0);
+
+    unsafe {
+        ralloc::free(ptr, 1 << 20);
+    }
+
+    ralloc::clear_high_water_marks();
+
+    // Exceeding the heap limit is no failure of the memory source.
+    ralloc::set_heap_limit(ralloc::stats().heap_size);
+    let res = ralloc::try_alloc(16 << 20, 1).is_err();
+    ralloc::set_heap_limit(!0);
+
+    assert!(res);
+    assert_eq!(FAILURES.load(Ordering::SeqCst), 0);
+
+    // A failing memory source (the request exceeds the address space). The request is retried,
+    // but the event is only raised once.
+    assert!(ralloc::try_alloc(usize::max_value() / 4, 1).is_err());
+    assert_eq!(FAILURES.load(Ordering::SeqCst), 1);
+
+    ralloc::remove_pressure_callback(callback).unwrap();
+}
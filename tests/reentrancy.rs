@@ -0,0 +1,65 @@
+extern crate ralloc;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use ralloc::{OomAction, OomInfo};
+
+static CALLED: AtomicBool = AtomicBool::new(false);
+static BUFFER: AtomicUsize = AtomicUsize::new(0);
+
+fn corruption(_: ralloc::Corruption) {
+    unsafe {
+        // The allocator is in use, so this is served by the emergency arena.
+        let ptr = ralloc::alloc(32, 8);
+        *ptr = 0xAA;
+
+        let ptr = ralloc::realloc(ptr, 32, 64, 8);
+        assert_eq!(*ptr, 0xAA);
+        ralloc::free(ptr, 64);
+
+        // This is deferred until the allocator is left.
+        ralloc::free(BUFFER.load(Ordering::SeqCst) as *mut u8, 16);
+    }
+
+    CALLED.store(true, Ordering::SeqCst);
+}
+
+#[test]
+#[cfg(any(debug_assertions, feature = "hardened"))]
+fn alloc_in_corruption_handler() {
+    ralloc::set_corruption_handler(corruption);
+
+    BUFFER.store(ralloc::alloc(16, 8) as usize, Ordering::SeqCst);
+    let ptr = ralloc::alloc(16, 8);
+
+    unsafe {
+        ralloc::free(ptr, 16);
+        ralloc::free(ptr, 16);
+    }
+
+    assert!(CALLED.load(Ordering::SeqCst));
+
+    // The allocator is still usable.
+    let ptr = ralloc::alloc(16, 8);
+    unsafe { ralloc::free(ptr, 16); }
+}
+
+fn oom(info: &OomInfo) -> OomAction {
+    // Lift the limit, and allocate from inside the handler.
+    ralloc::set_thread_limit(!0);
+
+    let ptr = ralloc::alloc(info.size / 2, info.align);
+    assert!(!ptr.is_null());
+    unsafe { ralloc::free(ptr, info.size / 2); }
+
+    OomAction::Retry
+}
+
+#[test]
+fn alloc_in_oom_handler() {
+    ralloc::set_thread_oom_info_handler(oom);
+    ralloc::set_thread_limit(ralloc::thread_usage());
+
+    let ptr = ralloc::alloc(4096, 8);
+    unsafe { ralloc::free(ptr, 4096); }
+}
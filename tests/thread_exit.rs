@@ -0,0 +1,35 @@
+extern crate ralloc;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+static CALLED: AtomicBool = AtomicBool::new(false);
+
+fn corruption(_: ralloc::Corruption) {
+    // The thread destructor holds the global allocator, so this is served by the emergency arena.
+    let ptr = ralloc::alloc(32, 8);
+    unsafe { ralloc::free(ptr, 32); }
+
+    CALLED.store(true, Ordering::SeqCst);
+}
+
+// The handler is global, hence this is kept as a single test.
+#[test]
+#[cfg(all(feature = "quarantine", feature = "poison"))]
+fn alloc_in_thread_destructor() {
+    ralloc::set_corruption_handler(corruption);
+
+    thread::spawn(|| {
+        let ptr = ralloc::alloc(16, 8);
+
+        unsafe {
+            ralloc::free(ptr, 16);
+
+            // Write to the quarantined buffer. This is detected, when the thread destructor frees
+            // the quarantine.
+            *ptr = 0;
+        }
+    }).join().unwrap();
+
+    assert!(CALLED.load(Ordering::SeqCst));
+}
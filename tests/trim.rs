@@ -0,0 +1,28 @@
+extern crate ralloc;
+
+// The trims are observed through the global allocator, hence this is kept as a single test.
+#[test]
+fn trim() {
+    let ptrs: Vec<_> = (0..100).map(|_| ralloc::alloc(64, 8)).collect();
+
+    for &ptr in &ptrs {
+        unsafe {
+            ralloc::free(ptr, 64);
+        }
+    }
+
+    // After draining, the local allocator holds nothing.
+    ralloc::trim_thread();
+    assert_eq!(ralloc::trim_thread().to_global, 0);
+
+    // A big buffer on the top of the heap is released to the OS.
+    let ptr = ralloc::alloc(16 << 20, 1);
+    unsafe {
+        ralloc::free(ptr, 16 << 20);
+    }
+
+    assert!(ralloc::trim().to_os > 0);
+
+    // Nothing is left to release, even if pages are purged again.
+    assert_eq!(ralloc::trim().to_os, 0);
+}
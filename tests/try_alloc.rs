@@ -0,0 +1,59 @@
+extern crate ralloc;
+
+mod util;
+
+use ralloc::AllocError;
+
+#[test]
+fn try_alloc() {
+    util::multiply(|| {
+        let ptr = ralloc::try_alloc(64, 8).unwrap().as_ptr();
+        assert_eq!(0, ptr as usize % 8);
+
+        unsafe {
+            util::acid(|| {
+                *ptr = 0xAA;
+                *ptr.offset(63) = 0xBB;
+            });
+
+            let ptr = ralloc::try_realloc(ptr, 64, 200, 8).unwrap().as_ptr();
+            assert_eq!(*ptr, 0xAA);
+            assert_eq!(*ptr.offset(63), 0xBB);
+
+            ralloc::free(ptr, 200);
+        }
+    });
+}
+
+#[test]
+fn try_alloc_zeroed() {
+    util::multiply(|| {
+        let ptr = ralloc::try_alloc_zeroed(300, 4).unwrap().as_ptr();
+
+        unsafe {
+            for i in 0..300 {
+                assert_eq!(*ptr.offset(i), 0);
+            }
+
+            ralloc::free(ptr, 300);
+        }
+    });
+}
+
+#[test]
+fn try_alloc_errors() {
+    assert_eq!(ralloc::try_alloc(!0, 1).err(), Some(AllocError::SizeOverflow));
+    assert_eq!(ralloc::try_alloc(8, 0).err(), Some(AllocError::InvalidAlignment));
+
+    let ptr = ralloc::alloc(16, 1);
+
+    unsafe {
+        *ptr = 0xAA;
+
+        // The old buffer must be left intact on failure.
+        assert_eq!(ralloc::try_realloc(ptr, 16, !0 - 4096, 1).err(), Some(AllocError::SizeOverflow));
+        assert_eq!(*ptr, 0xAA);
+
+        ralloc::free(ptr, 16);
+    }
+}
@@ -0,0 +1,32 @@
+extern crate ralloc;
+
+use ralloc::config::{self, Param};
+
+fn corrupted(corruption: ralloc::Corruption) {
+    panic!("Unexpected corruption: {}", corruption);
+}
+
+// The verification interval is global, hence this is kept as a single test.
+#[test]
+fn verify() {
+    ralloc::set_corruption_handler(corrupted);
+
+    let mut ptrs = Vec::new();
+    for i in 1..64 {
+        ptrs.push((ralloc::alloc(i * 8, 8), i * 8));
+    }
+    assert_eq!(ralloc::verify_heap(), Ok(()));
+
+    // Verify on every operation.
+    config::set(Param::VerifyInterval, 1);
+
+    for &(ptr, size) in ptrs.iter().step_by(2) {
+        unsafe { ralloc::free(ptr, size); }
+    }
+    for &(ptr, size) in ptrs.iter().skip(1).step_by(2) {
+        unsafe { ralloc::free(ptr, size); }
+    }
+
+    config::set(Param::VerifyInterval, 0);
+    assert_eq!(ralloc::verify_heap(), Ok(()));
+}